@@ -0,0 +1,721 @@
+use super::{AdvancedOptions, PartitionType, human_size};
+use std::fmt;
+use std::str::FromStr;
+
+pub(crate) const KIB: u64 = 1024;
+pub(crate) const MIB: u64 = 1024 * KIB;
+pub(crate) const GIB: u64 = 1024 * MIB;
+pub(crate) const TIB: u64 = 1024 * GIB;
+
+pub(crate) const FAT_SIZES: [u8; 3] = [12, 16, 32];
+pub(crate) const BTRFS_METADATA_PROFILES: [&str; 2] = ["single", "dup"];
+pub(crate) const BTRFS_CHECKSUMS: [&str; 4] = ["crc32c", "xxhash", "sha256", "blake2"];
+
+const FAT_INVALID_CHARS: [char; 10] = ['"', '*', '/', ':', '<', '>', '?', '\\', '|', '\0'];
+
+/// A filesystem diskfmt can create. Everything that depends on the filesystem
+/// lives in its [`FsInfo`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Filesystem {
+    Vfat,
+    Exfat,
+    Ntfs,
+    Ext4,
+    Ext3,
+    Ext2,
+    Xfs,
+    Btrfs,
+    F2fs,
+    Udf,
+    HfsPlus,
+    Swap,
+}
+
+/// How the length of a label is counted.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum LabelLimit {
+    Bytes(usize),
+    Chars(usize),
+    Utf16Units(usize),
+    /// 126 characters if the label fits in Latin-1, 63 otherwise (stored as
+    /// UTF-16).
+    Udf,
+}
+
+/// Characters a label must not contain, on top of NUL.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum LabelChars {
+    Any,
+    /// The FAT/exFAT reserved set and control characters.
+    Fat,
+    NoSlash,
+    NoColon,
+}
+
+/// The allocation unit the user can pick, if any.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum AllocUnit {
+    None,
+    Sectors(&'static [u64]),
+    Bytes(&'static [u64]),
+}
+
+/// Whose defaults an allocation unit recommendation follows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AllocProfile {
+    /// What Windows' format picks for the volume size.
+    Windows,
+    /// The SD Association's recommendations for SD cards.
+    SdCard,
+}
+
+// Recommendation tables: (largest volume size, allocation unit), first match
+// wins. vfat units are sectors, the rest bytes.
+const WINDOWS_FAT32: &[(u64, u64)] = &[
+    (64 * MIB, 1),
+    (128 * MIB, 2),
+    (256 * MIB, 4),
+    (8 * GIB, 8),
+    (16 * GIB, 16),
+    (32 * GIB, 32),
+    (2 * TIB, 64),
+];
+const WINDOWS_EXFAT: &[(u64, u64)] = &[(256 * MIB, 4096), (32 * GIB, 32768), (256 * TIB, 131072)];
+const WINDOWS_NTFS: &[(u64, u64)] = &[
+    (16 * TIB, 4096),
+    (32 * TIB, 8192),
+    (64 * TIB, 16384),
+    (128 * TIB, 32768),
+    (256 * TIB, 65536),
+];
+const SD_FAT: &[(u64, u64)] = &[(GIB, 32), (32 * GIB, 64)];
+const SD_EXFAT: &[(u64, u64)] = &[(512 * GIB, 131072), (2 * TIB, 262144)];
+// mke2fs.conf's "small" type uses 1 KiB blocks below 512 MiB.
+const EXT_BLOCKS: &[(u64, u64)] = &[(512 * MIB, 1024), (u64::MAX, 4096)];
+
+fn lookup(table: &[(u64, u64)], size: u64) -> Option<u64> {
+    table
+        .iter()
+        .find(|&&(max, _)| size <= max)
+        .map(|&(_, unit)| unit)
+}
+
+/// Shape of the filesystem UUID or volume serial.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum VolumeIdKind {
+    /// Can't be chosen.
+    None,
+    /// An RFC 4122 UUID.
+    Uuid,
+    /// A 32-bit serial, written "XXXX-XXXX" (FAT, exFAT).
+    Serial32,
+    /// A 64-bit serial as 16 hex digits (NTFS, UDF).
+    Serial64,
+}
+
+/// Static description of a filesystem.
+#[derive(Debug)]
+pub(crate) struct FsInfo {
+    /// Name used on the command line, in the UI and as the UDisks
+    /// `Block.Format` type.
+    pub(crate) name: &'static str,
+    pub(crate) label_limit: LabelLimit,
+    pub(crate) label_chars: LabelChars,
+    /// Caption of the allocation unit selector.
+    pub(crate) alloc_caption: &'static str,
+    pub(crate) alloc_unit: AllocUnit,
+    /// Any of these being installed is enough to create the filesystem.
+    pub(crate) mkfs_tools: &'static [&'static str],
+    /// mkfs option taking the allocation unit when formatting through
+    /// `Block.Format` directly. The size is appended to it, getopt style
+    /// ("-s8").
+    pub(crate) mkfs_size_arg: Option<&'static str>,
+    pub(crate) volume_id: VolumeIdKind,
+    /// mkfs option taking the volume ID, appended the same way. Without one
+    /// the ID is set with `Filesystem.SetUUID` after formatting.
+    pub(crate) mkfs_volume_id_arg: Option<&'static str>,
+    pub(crate) min_volume_size: Option<u64>,
+    pub(crate) max_volume_size: Option<u64>,
+}
+
+static VFAT: FsInfo = FsInfo {
+    name: "vfat",
+    label_limit: LabelLimit::Bytes(11),
+    label_chars: LabelChars::Fat,
+    alloc_caption: "Sectors per cluster",
+    alloc_unit: AllocUnit::Sectors(&[1, 2, 4, 8, 16, 32, 64, 128]),
+    mkfs_tools: &["mkfs.vfat"],
+    mkfs_size_arg: Some("-s"),
+    volume_id: VolumeIdKind::Serial32,
+    mkfs_volume_id_arg: Some("-i"),
+    min_volume_size: None,
+    max_volume_size: None,
+};
+
+static EXFAT: FsInfo = FsInfo {
+    name: "exfat",
+    label_limit: LabelLimit::Chars(15),
+    label_chars: LabelChars::Fat,
+    alloc_caption: "Cluster size (bytes)",
+    alloc_unit: AllocUnit::Bytes(&[
+        4096, 8192, 16384, 32768, 65536, 131072, 262144, 524288, 1048576,
+    ]),
+    mkfs_tools: &["mkfs.exfat"],
+    mkfs_size_arg: Some("-c"),
+    volume_id: VolumeIdKind::Serial32,
+    mkfs_volume_id_arg: None,
+    min_volume_size: None,
+    max_volume_size: None,
+};
+
+static NTFS: FsInfo = FsInfo {
+    name: "ntfs",
+    label_limit: LabelLimit::Chars(32),
+    label_chars: LabelChars::Any,
+    alloc_caption: "Cluster size (bytes)",
+    alloc_unit: AllocUnit::Bytes(&[
+        4096, 8192, 16384, 32768, 65536, 131072, 262144, 524288, 1048576,
+    ]),
+    mkfs_tools: &["mkfs.ntfs", "mkntfs"],
+    mkfs_size_arg: Some("-c"),
+    volume_id: VolumeIdKind::Serial64,
+    mkfs_volume_id_arg: None,
+    min_volume_size: None,
+    max_volume_size: Some(256 * TIB),
+};
+
+const EXT_BLOCK_SIZES: &[u64] = &[1024, 2048, 4096];
+
+static EXT4: FsInfo = FsInfo {
+    name: "ext4",
+    label_limit: LabelLimit::Bytes(16),
+    label_chars: LabelChars::NoSlash,
+    alloc_caption: "Block size (bytes)",
+    alloc_unit: AllocUnit::Bytes(EXT_BLOCK_SIZES),
+    mkfs_tools: &["mkfs.ext4", "mke2fs"],
+    mkfs_size_arg: Some("-b"),
+    volume_id: VolumeIdKind::Uuid,
+    mkfs_volume_id_arg: Some("-U"),
+    min_volume_size: None,
+    max_volume_size: None,
+};
+
+static EXT3: FsInfo = FsInfo {
+    name: "ext3",
+    label_limit: LabelLimit::Bytes(16),
+    label_chars: LabelChars::NoSlash,
+    alloc_caption: "Block size (bytes)",
+    alloc_unit: AllocUnit::Bytes(EXT_BLOCK_SIZES),
+    mkfs_tools: &["mkfs.ext3", "mke2fs"],
+    mkfs_size_arg: Some("-b"),
+    volume_id: VolumeIdKind::Uuid,
+    mkfs_volume_id_arg: Some("-U"),
+    min_volume_size: None,
+    max_volume_size: Some(16 * TIB),
+};
+
+static EXT2: FsInfo = FsInfo {
+    name: "ext2",
+    label_limit: LabelLimit::Bytes(16),
+    label_chars: LabelChars::NoSlash,
+    alloc_caption: "Block size (bytes)",
+    alloc_unit: AllocUnit::Bytes(EXT_BLOCK_SIZES),
+    mkfs_tools: &["mkfs.ext2", "mke2fs"],
+    mkfs_size_arg: Some("-b"),
+    volume_id: VolumeIdKind::Uuid,
+    mkfs_volume_id_arg: Some("-U"),
+    min_volume_size: None,
+    max_volume_size: Some(16 * TIB),
+};
+
+static XFS: FsInfo = FsInfo {
+    name: "xfs",
+    label_limit: LabelLimit::Bytes(12),
+    label_chars: LabelChars::Any,
+    alloc_caption: "Block size (bytes)",
+    alloc_unit: AllocUnit::Bytes(EXT_BLOCK_SIZES),
+    mkfs_tools: &["mkfs.xfs"],
+    mkfs_size_arg: Some("-bsize="),
+    volume_id: VolumeIdKind::Uuid,
+    mkfs_volume_id_arg: Some("-muuid="),
+    // xfsprogs refuses filesystems below 300 MiB.
+    min_volume_size: Some(300 * MIB),
+    max_volume_size: None,
+};
+
+static BTRFS: FsInfo = FsInfo {
+    name: "btrfs",
+    label_limit: LabelLimit::Bytes(255),
+    label_chars: LabelChars::Any,
+    alloc_caption: "Nodesize (bytes)",
+    alloc_unit: AllocUnit::Bytes(&[4096, 16384, 32768, 65536]),
+    mkfs_tools: &["mkfs.btrfs"],
+    mkfs_size_arg: Some("-n"),
+    volume_id: VolumeIdKind::Uuid,
+    mkfs_volume_id_arg: Some("-U"),
+    min_volume_size: Some(114 * MIB),
+    max_volume_size: None,
+};
+
+static F2FS: FsInfo = FsInfo {
+    name: "f2fs",
+    label_limit: LabelLimit::Utf16Units(512),
+    label_chars: LabelChars::Any,
+    alloc_caption: "Allocation Unit Size",
+    alloc_unit: AllocUnit::None,
+    mkfs_tools: &["mkfs.f2fs"],
+    mkfs_size_arg: None,
+    volume_id: VolumeIdKind::Uuid,
+    mkfs_volume_id_arg: Some("-U"),
+    min_volume_size: None,
+    max_volume_size: Some(16 * TIB),
+};
+
+static UDF: FsInfo = FsInfo {
+    name: "udf",
+    label_limit: LabelLimit::Udf,
+    label_chars: LabelChars::Any,
+    alloc_caption: "Block size (bytes)",
+    alloc_unit: AllocUnit::Bytes(&[512, 1024, 2048, 4096]),
+    mkfs_tools: &["mkudffs", "mkfs.udf"],
+    mkfs_size_arg: Some("-b"),
+    volume_id: VolumeIdKind::Serial64,
+    mkfs_volume_id_arg: Some("--uuid="),
+    min_volume_size: None,
+    max_volume_size: None,
+};
+
+static HFSPLUS: FsInfo = FsInfo {
+    name: "hfsplus",
+    label_limit: LabelLimit::Utf16Units(255),
+    label_chars: LabelChars::NoColon,
+    alloc_caption: "Block size (bytes)",
+    alloc_unit: AllocUnit::Bytes(&[512, 1024, 2048, 4096, 8192, 16384, 32768, 65536]),
+    mkfs_tools: &["mkfs.hfsplus"],
+    mkfs_size_arg: Some("-b"),
+    volume_id: VolumeIdKind::None,
+    mkfs_volume_id_arg: None,
+    min_volume_size: None,
+    max_volume_size: None,
+};
+
+static SWAP: FsInfo = FsInfo {
+    name: "swap",
+    label_limit: LabelLimit::Bytes(16),
+    label_chars: LabelChars::Any,
+    alloc_caption: "Allocation Unit Size",
+    alloc_unit: AllocUnit::None,
+    mkfs_tools: &["mkswap"],
+    mkfs_size_arg: None,
+    volume_id: VolumeIdKind::Uuid,
+    mkfs_volume_id_arg: Some("-U"),
+    min_volume_size: None,
+    max_volume_size: None,
+};
+
+impl Filesystem {
+    /// All filesystems, in the order they are offered.
+    pub(crate) const ALL: [Filesystem; 12] = [
+        Filesystem::Exfat,
+        Filesystem::Vfat,
+        Filesystem::Ntfs,
+        Filesystem::Ext4,
+        Filesystem::Ext3,
+        Filesystem::Ext2,
+        Filesystem::Xfs,
+        Filesystem::Btrfs,
+        Filesystem::F2fs,
+        Filesystem::Udf,
+        Filesystem::HfsPlus,
+        Filesystem::Swap,
+    ];
+
+    /// Order in which a default filesystem is picked from the installed ones.
+    /// Never swap: it holds no files.
+    pub(crate) const PREFERENCE: [Filesystem; 11] = [
+        Filesystem::Exfat,
+        Filesystem::Vfat,
+        Filesystem::Ext4,
+        Filesystem::Ntfs,
+        Filesystem::Xfs,
+        Filesystem::Btrfs,
+        Filesystem::F2fs,
+        Filesystem::Ext3,
+        Filesystem::Ext2,
+        Filesystem::Udf,
+        Filesystem::HfsPlus,
+    ];
+
+    pub(crate) fn info(self) -> &'static FsInfo {
+        match self {
+            Filesystem::Vfat => &VFAT,
+            Filesystem::Exfat => &EXFAT,
+            Filesystem::Ntfs => &NTFS,
+            Filesystem::Ext4 => &EXT4,
+            Filesystem::Ext3 => &EXT3,
+            Filesystem::Ext2 => &EXT2,
+            Filesystem::Xfs => &XFS,
+            Filesystem::Btrfs => &BTRFS,
+            Filesystem::F2fs => &F2FS,
+            Filesystem::Udf => &UDF,
+            Filesystem::HfsPlus => &HFSPLUS,
+            Filesystem::Swap => &SWAP,
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        self.info().name
+    }
+
+    /// Names of all filesystems, for the CLI's possible values.
+    pub(crate) fn names() -> impl Iterator<Item = &'static str> {
+        Self::ALL.into_iter().map(Filesystem::name)
+    }
+
+    /// Type of the partition created for this filesystem when none is chosen.
+    pub(crate) fn partition_type(self) -> PartitionType {
+        match self {
+            Filesystem::Vfat | Filesystem::Exfat | Filesystem::Ntfs | Filesystem::Udf => {
+                PartitionType::MicrosoftBasicData
+            }
+            Filesystem::Swap => PartitionType::LinuxSwap,
+            Filesystem::HfsPlus => PartitionType::AppleHfsPlus,
+            _ => PartitionType::LinuxFilesystem,
+        }
+    }
+
+    pub(crate) fn validate_label(self, label: &str) -> Option<String> {
+        if label.is_empty() {
+            return None;
+        }
+        let info = self.info();
+        let name = info.name;
+
+        let (len, max, unit) = match info.label_limit {
+            LabelLimit::Bytes(max) => (label.len(), max, "bytes"),
+            LabelLimit::Chars(max) => (label.chars().count(), max, "characters"),
+            LabelLimit::Utf16Units(max) => (label.encode_utf16().count(), max, "characters"),
+            LabelLimit::Udf => {
+                let max = if label.chars().all(|c| (c as u32) <= 0xff) {
+                    126
+                } else {
+                    63
+                };
+                (label.chars().count(), max, "characters for this label")
+            }
+        };
+        if len > max {
+            return Some(format!("{name}: max {max} {unit}"));
+        }
+
+        let invalid = match info.label_chars {
+            LabelChars::Any => label.contains('\0'),
+            LabelChars::Fat => label
+                .chars()
+                .any(|c| FAT_INVALID_CHARS.contains(&c) || c.is_control()),
+            LabelChars::NoSlash => label.contains('\0') || label.contains('/'),
+            LabelChars::NoColon => label.contains('\0') || label.contains(':'),
+        };
+        if invalid {
+            return Some(format!("{name}: invalid characters"));
+        }
+        None
+    }
+
+    /// Checks a chosen allocation unit against the ones the filesystem offers.
+    pub(crate) fn validate_alloc_unit(self, size: u64) -> Option<String> {
+        let name = self.name();
+        match self.info().alloc_unit {
+            AllocUnit::None => Some(format!("{name} has no allocation unit size option")),
+            AllocUnit::Sectors(choices) | AllocUnit::Bytes(choices) if !choices.contains(&size) => {
+                let list: Vec<String> = choices.iter().map(u64::to_string).collect();
+                Some(format!(
+                    "{name}: {} must be one of {}",
+                    self.info().alloc_caption.to_lowercase(),
+                    list.join(", ")
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks that a volume of `size` bytes is within what the filesystem
+    /// supports.
+    pub(crate) fn validate_volume_size(self, size: u64) -> Option<String> {
+        let info = self.info();
+        if let Some(min) = info.min_volume_size
+            && size < min
+        {
+            return Some(format!(
+                "{} needs at least {}, the target is {}",
+                info.name,
+                human_size(min),
+                human_size(size)
+            ));
+        }
+        if let Some(max) = info.max_volume_size
+            && size > max
+        {
+            return Some(format!(
+                "{} supports at most {}, the target is {}",
+                info.name,
+                human_size(max),
+                human_size(size)
+            ));
+        }
+        None
+    }
+
+    /// The allocation unit to use for a volume of `size` bytes, in the unit
+    /// of [`AllocUnit`]. `None` leaves the choice to mkfs.
+    pub(crate) fn recommended_alloc_unit(self, size: u64, profile: AllocProfile) -> Option<u64> {
+        match (self, profile) {
+            // Small enough that mkfs.fat picks FAT12/16.
+            (Filesystem::Vfat, _) if size < 32 * MIB => None,
+            (Filesystem::Vfat, AllocProfile::SdCard) => lookup(SD_FAT, size),
+            (Filesystem::Vfat, AllocProfile::Windows) => lookup(WINDOWS_FAT32, size),
+            (Filesystem::Exfat, AllocProfile::SdCard) => lookup(SD_EXFAT, size),
+            (Filesystem::Exfat, AllocProfile::Windows) => lookup(WINDOWS_EXFAT, size),
+            (Filesystem::Ntfs, _) => lookup(WINDOWS_NTFS, size),
+            (Filesystem::Ext4 | Filesystem::Ext3 | Filesystem::Ext2, _) => lookup(EXT_BLOCKS, size),
+            (Filesystem::Xfs | Filesystem::HfsPlus, _) => Some(4096),
+            (Filesystem::Btrfs, _) => Some(16384),
+            (Filesystem::F2fs | Filesystem::Udf | Filesystem::Swap, _) => None,
+        }
+    }
+
+    /// Checks a UUID or volume serial against the filesystem's format and
+    /// returns it in canonical form.
+    pub(crate) fn parse_volume_id(self, s: &str) -> Result<String, String> {
+        let name = self.name();
+        let kind = self.info().volume_id;
+        let hex: String = s.chars().filter(|&c| c != '-').collect();
+        let digits = match kind {
+            VolumeIdKind::None => {
+                return Err(format!("{name}: the volume ID can't be set"));
+            }
+            VolumeIdKind::Uuid => 32,
+            VolumeIdKind::Serial32 => 8,
+            VolumeIdKind::Serial64 => 16,
+        };
+        if hex.len() != digits || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            let expected = match kind {
+                VolumeIdKind::Uuid => "a UUID",
+                VolumeIdKind::Serial32 => "8 hex digits (e.g. 1234-ABCD)",
+                _ => "16 hex digits",
+            };
+            return Err(format!("{name}: volume ID must be {expected}, got '{s}'"));
+        }
+        Ok(format_volume_id(kind, &hex))
+    }
+
+    /// Derives a volume ID from `seed`, so the same seed always recreates the
+    /// same ID.
+    pub(crate) fn volume_id_from_seed(self, seed: &str) -> Result<String, String> {
+        let kind = self.info().volume_id;
+        if kind == VolumeIdKind::None {
+            return Err(format!("{}: the volume ID can't be set", self.name()));
+        }
+        // FNV-1a, then splitmix64: stable across builds, unlike std's hasher.
+        let mut state = seed.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |h, b| {
+            (h ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+        });
+        let mut next = || {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        };
+        let (hi, lo) = (next(), next());
+        let hex = match kind {
+            // Version 4, RFC 4122 variant.
+            VolumeIdKind::Uuid => format!(
+                "{:016x}{:016x}",
+                (hi & !0xf000) | 0x4000,
+                (lo & !(0xc << 60)) | (0x8 << 60)
+            ),
+            VolumeIdKind::Serial32 => format!("{:08x}", hi >> 32),
+            _ => format!("{hi:016x}"),
+        };
+        Ok(format_volume_id(kind, &hex))
+    }
+
+    pub(crate) fn validate_advanced(self, adv: &AdvancedOptions) -> Option<String> {
+        let name = self.name();
+        let is_ext = matches!(self, Filesystem::Ext4 | Filesystem::Ext3 | Filesystem::Ext2);
+        let options = [
+            ("reserved blocks", adv.reserved_percent.is_some(), is_ext),
+            ("inode ratio", adv.inode_ratio.is_some(), is_ext),
+            ("feature flags", !adv.ext_features.is_empty(), is_ext),
+            ("journal", adv.journal.is_some(), self == Filesystem::Ext4),
+            ("FAT size", adv.fat_size.is_some(), self == Filesystem::Vfat),
+            (
+                "volume ID",
+                adv.volume_id.is_some(),
+                self.info().volume_id != VolumeIdKind::None,
+            ),
+            (
+                "metadata profile",
+                adv.btrfs_metadata.is_some(),
+                self == Filesystem::Btrfs,
+            ),
+            (
+                "checksum",
+                adv.btrfs_checksum.is_some(),
+                self == Filesystem::Btrfs,
+            ),
+            (
+                "compression",
+                adv.ntfs_compression,
+                self == Filesystem::Ntfs,
+            ),
+            (
+                "reflink",
+                adv.xfs_reflink.is_some(),
+                self == Filesystem::Xfs,
+            ),
+            ("crc", adv.xfs_crc.is_some(), self == Filesystem::Xfs),
+        ];
+        if let Some((what, _, _)) = options.iter().find(|(_, set, applies)| *set && !applies) {
+            return Some(format!("{name} has no {what} option"));
+        }
+
+        if let Some(pct) = adv.reserved_percent
+            && !(0.0..=50.0).contains(&pct)
+        {
+            return Some(format!("{name}: reserved blocks must be 0-50%"));
+        }
+        if let Some(ratio) = adv.inode_ratio
+            && !(1024..=64 * MIB).contains(&ratio)
+        {
+            return Some(format!("{name}: inode ratio must be 1024-67108864 bytes"));
+        }
+        if let Some(bad) = adv.ext_features.iter().find(|f| {
+            let f = f.strip_prefix('^').unwrap_or(f);
+            f.is_empty()
+                || !f
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        }) {
+            return Some(format!("{name}: invalid feature flag '{bad}'"));
+        }
+        if let Some(id) = &adv.volume_id
+            && let Err(err) = self.parse_volume_id(id)
+        {
+            return Some(err);
+        }
+        if let Some(bits) = adv.fat_size
+            && !FAT_SIZES.contains(&bits)
+        {
+            return Some(format!("{name}: FAT size must be 12, 16 or 32"));
+        }
+        if let Some(profile) = &adv.btrfs_metadata
+            && !BTRFS_METADATA_PROFILES.contains(&profile.as_str())
+        {
+            return Some(format!(
+                "{name}: metadata profile must be one of {}",
+                BTRFS_METADATA_PROFILES.join(", ")
+            ));
+        }
+        if let Some(csum) = &adv.btrfs_checksum
+            && !BTRFS_CHECKSUMS.contains(&csum.as_str())
+        {
+            return Some(format!(
+                "{name}: checksum must be one of {}",
+                BTRFS_CHECKSUMS.join(", ")
+            ));
+        }
+        if adv.xfs_reflink == Some(true) && adv.xfs_crc == Some(false) {
+            return Some(format!("{name}: reflink requires crc"));
+        }
+        None
+    }
+
+    /// Extra mkfs arguments for the allocation unit and advanced options.
+    pub(crate) fn mkfs_args(self, block_size: Option<u64>, adv: &AdvancedOptions) -> Vec<String> {
+        let mut args = Vec::new();
+        if let (Some(bs), Some(arg)) = (block_size, self.info().mkfs_size_arg) {
+            args.push(format!("{arg}{bs}"));
+        }
+        if let Some(pct) = adv.reserved_percent {
+            args.push(format!("-m{pct}"));
+        }
+        if let Some(ratio) = adv.inode_ratio {
+            args.push(format!("-i{ratio}"));
+        }
+        let mut features = adv.ext_features.clone();
+        if adv.journal == Some(false) {
+            features.push("^has_journal".into());
+        }
+        if !features.is_empty() {
+            args.push(format!("-O{}", features.join(",")));
+        }
+        if let Some(bits) = adv.fat_size {
+            args.push(format!("-F{bits}"));
+        }
+        if let (Some(id), Some(arg)) = (&adv.volume_id, self.info().mkfs_volume_id_arg) {
+            let id = match self.info().volume_id {
+                VolumeIdKind::Uuid => id.clone(),
+                _ => id.replace('-', "").to_ascii_lowercase(),
+            };
+            args.push(format!("{arg}{id}"));
+        }
+        if let Some(profile) = &adv.btrfs_metadata {
+            args.push(format!("-m{profile}"));
+        }
+        if let Some(csum) = &adv.btrfs_checksum {
+            args.push(format!("--csum={csum}"));
+        }
+        if adv.ntfs_compression {
+            args.push("-C".into());
+        }
+        let xfs_meta: Vec<String> = [("crc", adv.xfs_crc), ("reflink", adv.xfs_reflink)]
+            .into_iter()
+            .filter_map(|(k, v)| v.map(|on| format!("{k}={}", u8::from(on))))
+            .collect();
+        if !xfs_meta.is_empty() {
+            args.push(format!("-m{}", xfs_meta.join(",")));
+        }
+        args
+    }
+}
+
+/// Canonical spelling of a volume ID given as bare hex digits.
+fn format_volume_id(kind: VolumeIdKind, hex: &str) -> String {
+    match kind {
+        VolumeIdKind::Uuid => {
+            let h = hex.to_ascii_lowercase();
+            format!(
+                "{}-{}-{}-{}-{}",
+                &h[..8],
+                &h[8..12],
+                &h[12..16],
+                &h[16..20],
+                &h[20..]
+            )
+        }
+        VolumeIdKind::Serial32 => {
+            let h = hex.to_ascii_uppercase();
+            format!("{}-{}", &h[..4], &h[4..])
+        }
+        VolumeIdKind::Serial64 | VolumeIdKind::None => hex.to_ascii_uppercase(),
+    }
+}
+
+impl fmt::Display for Filesystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Filesystem {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|fs| fs.name() == s)
+            .ok_or_else(|| format!("unsupported filesystem '{s}'"))
+    }
+}
@@ -1,5 +1,7 @@
+use super::steps::{Geometry, Step, format_steps, plan_steps};
 use super::*;
 use crate::common::{Msg, UiSender};
+use crate::config::SafetyConfig;
 use tokio::time::{Duration, sleep};
 
 const MOCK_QUICK_OPERATION_MS: u64 = 100;
@@ -7,51 +9,178 @@ const MOCK_FORMAT_OPERATION_MS: u64 = 1000;
 
 pub(crate) struct MockBackend {
     ui_tx: crossbeam_channel::Sender<Msg>,
+    safety: SafetyConfig,
 }
 
 impl MockBackend {
-    pub(crate) fn new(ui_tx: crossbeam_channel::Sender<Msg>) -> Self {
+    pub(crate) fn new(ui_tx: crossbeam_channel::Sender<Msg>, safety: SafetyConfig) -> Self {
         ui_tx.emit(Msg::Status(
             "Warning: Using mock backend. UDisks2 unavailable.".to_string(),
         ));
-        Self { ui_tx }
+        Self { ui_tx, safety }
+    }
+
+    /// The device at `obj_path`, its geometry and the steps formatting it
+    /// with `opts` takes.
+    async fn steps(
+        &self,
+        obj_path: &str,
+        opts: &FormatOptions,
+    ) -> Result<(BlockDevice, Option<Geometry>, Vec<Step>)> {
+        let devs = self.list_block_devices(true).await?;
+        let Some(dev) = devs.into_iter().find(|d| d.object_path == obj_path) else {
+            bail!("{obj_path} is gone or not allowed by the [safety] config");
+        };
+        if let Some(why) = &dev.system_use {
+            bail!("{obj_path} {why}; the running system depends on it");
+        }
+        let disk = (!dev.is_partition)
+            .then(|| Geometry::new(dev.size_bytes.unwrap_or(0), opts.alignment, None));
+        let steps = format_steps(opts, disk)?;
+        Ok((dev, disk, steps))
     }
 }
 
 #[async_trait]
 impl Backend for MockBackend {
-    async fn list_block_devices(&self) -> Result<Vec<BlockDevice>> {
+    async fn list_block_devices(&self, include_fixed: bool) -> Result<Vec<BlockDevice>> {
         sleep(Duration::from_millis(MOCK_QUICK_OPERATION_MS)).await;
-        Ok(vec![BlockDevice {
-            dev_path: "/dev/sdc1".to_string(),
-            object_path: "0".to_string(),
-            fs_type: Some("vfat".into()),
-            label: Some("MOCK".into()),
-            size_bytes: Some(64 * 1_000_000_000),
-            vendor_model: Some("Mock USB".into()),
-            is_partition: true,
-        }])
-    }
-    async fn format(&self, _obj_path: &str, _opts: FormatOptions) -> Result<String> {
+        let devs = vec![
+            BlockDevice {
+                dev_path: "/dev/sdc1".to_string(),
+                object_path: "0".to_string(),
+                fs_type: Some("vfat".into()),
+                label: Some("MOCK".into()),
+                size_bytes: Some(64 * 1_000_000_000),
+                vendor_model: Some("Mock USB".into()),
+                is_partition: true,
+                removable: true,
+                bus: Some("usb".into()),
+                sd_card: false,
+                partition_name: Some("Mock data".into()),
+                partition_offset: Some(1024 * 1024),
+                table_object: Some("mock-sdc".into()),
+                disk_path: Some("/dev/sdc".into()),
+                mount_points: vec!["/media/mock/MOCK".into()],
+                uuid: Some("1234-ABCD".into()),
+                serial: Some("MOCK0001".into()),
+                wwn: None,
+                links: vec!["/dev/disk/by-id/usb-Mock_USB_MOCK0001-0:0-part1".into()],
+                member_of: None,
+                system_use: None,
+            },
+            BlockDevice {
+                dev_path: "/dev/sdd".to_string(),
+                object_path: "1".to_string(),
+                fs_type: None,
+                label: None,
+                size_bytes: Some(2_000_000_000_000),
+                vendor_model: Some("Mock HDD".into()),
+                is_partition: false,
+                removable: false,
+                bus: Some("usb".into()),
+                sd_card: false,
+                partition_name: None,
+                partition_offset: None,
+                table_object: None,
+                disk_path: None,
+                mount_points: Vec::new(),
+                uuid: None,
+                serial: Some("MOCK0002".into()),
+                wwn: None,
+                links: vec!["/dev/disk/by-id/usb-Mock_HDD_MOCK0002-0:0".into()],
+                member_of: None,
+                system_use: None,
+            },
+            BlockDevice {
+                dev_path: "/dev/sdd1".to_string(),
+                object_path: "2".to_string(),
+                fs_type: Some("LVM2_member".into()),
+                label: None,
+                size_bytes: Some(2_000_000_000_000 - 1024 * 1024),
+                vendor_model: Some("Mock HDD".into()),
+                is_partition: true,
+                removable: false,
+                bus: Some("usb".into()),
+                sd_card: false,
+                partition_name: None,
+                partition_offset: Some(1024 * 1024),
+                table_object: Some("1".into()),
+                disk_path: Some("/dev/sdd".into()),
+                mount_points: Vec::new(),
+                uuid: Some("Mock-PV-UUID".into()),
+                serial: Some("MOCK0002".into()),
+                wwn: None,
+                links: vec!["/dev/disk/by-id/usb-Mock_HDD_MOCK0002-0:0-part1".into()],
+                member_of: Some("backup".into()),
+                system_use: None,
+            },
+        ];
+        Ok(devs
+            .into_iter()
+            .filter(|d| d.removable || include_fixed)
+            .filter(|d| self.safety.refusal(&d.fingerprint(), &d.links).is_none())
+            .collect())
+    }
+    async fn format(&self, obj_path: &str, opts: FormatOptions) -> Result<String> {
+        let (dev, _, steps) = self.steps(obj_path, &opts).await?;
+        if let Some(expected) = &opts.expected {
+            expected.verify(&dev.fingerprint(), obj_path)?;
+        }
         let job_id = "mock_job_123".to_string();
         let _ = self
             .ui_tx
             .emit(Msg::Progress(ProgressEvent::JobStarted(job_id)));
-        sleep(Duration::from_millis(MOCK_FORMAT_OPERATION_MS)).await;
-        let _ = self.ui_tx.emit(Msg::Progress(ProgressEvent::Percent(25.0)));
-        sleep(Duration::from_millis(MOCK_FORMAT_OPERATION_MS)).await;
-        let _ = self.ui_tx.emit(Msg::Progress(ProgressEvent::Percent(50.0)));
-        sleep(Duration::from_millis(MOCK_FORMAT_OPERATION_MS)).await;
-        let _ = self.ui_tx.emit(Msg::Progress(ProgressEvent::Percent(75.0)));
-        sleep(Duration::from_millis(MOCK_FORMAT_OPERATION_MS)).await;
-        let _ = self
-            .ui_tx
-            .emit(Msg::Progress(ProgressEvent::Percent(100.0)));
+        for step in &steps {
+            if let Some(msg) = step.message() {
+                self.ui_tx.emit(Msg::Progress(ProgressEvent::Message(msg)));
+            }
+            if !matches!(step, Step::Format { .. }) {
+                sleep(Duration::from_millis(MOCK_QUICK_OPERATION_MS)).await;
+                continue;
+            }
+            for percent in [25.0, 50.0, 75.0, 100.0] {
+                sleep(Duration::from_millis(MOCK_FORMAT_OPERATION_MS)).await;
+                let _ = self
+                    .ui_tx
+                    .emit(Msg::Progress(ProgressEvent::Percent(percent)));
+            }
+        }
         let _ = self
             .ui_tx
             .emit(Msg::Progress(ProgressEvent::Completed(Ok(()))));
         Ok("Done".to_string())
     }
+    async fn plan_format(&self, obj_path: &str, opts: &FormatOptions) -> Result<Vec<String>> {
+        let (dev, disk, steps) = self.steps(obj_path, opts).await?;
+        Ok(plan_steps(&steps, &dev.dev_path, disk, |volume, _, _| {
+            format!("{} (mock backend, options not resolved)", volume.opts.fs)
+        }))
+    }
+    async fn set_label(&self, _obj_path: &str, _label: &str) -> Result<()> {
+        sleep(Duration::from_millis(MOCK_QUICK_OPERATION_MS)).await;
+        Ok(())
+    }
+    async fn check(&self, _obj_path: &str) -> Result<FsckReport> {
+        self.ui_tx.emit(Msg::Progress(ProgressEvent::JobStarted(
+            "mock_check_123".into(),
+        )));
+        sleep(Duration::from_millis(MOCK_FORMAT_OPERATION_MS)).await;
+        let report = FsckReport {
+            had_errors: false,
+            repair_attempted: false,
+            consistent: true,
+        };
+        self.ui_tx.emit(Msg::Progress(ProgressEvent::Message(
+            report.summary().into(),
+        )));
+        self.ui_tx
+            .emit(Msg::Progress(ProgressEvent::Completed(Ok(()))));
+        Ok(report)
+    }
+    async fn repair(&self, obj_path: &str) -> Result<FsckReport> {
+        self.check(obj_path).await
+    }
     async fn cancel(&self, _job_id: &str) -> Result<()> {
         sleep(Duration::from_millis(MOCK_QUICK_OPERATION_MS)).await;
         Ok(())
@@ -1,7 +1,13 @@
+pub(crate) mod filesystem;
 pub(crate) mod mock;
+pub(crate) mod sd;
+pub(crate) mod steps;
+pub(crate) mod system;
 pub(crate) mod udisks;
-use anyhow::Result;
+use anyhow::{Result, bail};
 use async_trait::async_trait;
+pub(crate) use filesystem::Filesystem;
+use std::fmt;
 
 #[derive(Clone, Debug)]
 pub(crate) struct BlockDevice {
@@ -12,15 +18,217 @@ pub(crate) struct BlockDevice {
     pub(crate) size_bytes: Option<u64>,
     pub(crate) vendor_model: Option<String>,
     pub(crate) is_partition: bool,
+    /// The drive reports removable media. Fixed drives are only listed on
+    /// request.
+    pub(crate) removable: bool,
+    /// How the drive is connected ("usb", "sdio", "ieee1394"), if known.
+    pub(crate) bus: Option<String>,
+    /// The drive takes SD or MMC cards: a built-in slot or a card reader.
+    pub(crate) sd_card: bool,
+    /// GPT partition name, if the device is a named partition.
+    pub(crate) partition_name: Option<String>,
+    /// Start of the partition on its disk, in bytes.
+    pub(crate) partition_offset: Option<u64>,
+    /// For partitions, the object path of the disk whose partition table
+    /// holds it, and that disk's device node.
+    pub(crate) table_object: Option<String>,
+    pub(crate) disk_path: Option<String>,
+    /// Where the filesystem on the device is mounted.
+    pub(crate) mount_points: Vec<String>,
+    /// Filesystem UUID or volume serial.
+    pub(crate) uuid: Option<String>,
+    /// Serial number of the drive the device is on.
+    pub(crate) serial: Option<String>,
+    /// World Wide Name of the drive the device is on.
+    pub(crate) wwn: Option<String>,
+    /// `/dev/disk/by-*` links to the device.
+    pub(crate) links: Vec<String>,
+    /// For RAID, LVM and ZFS members, the array, volume group or pool the
+    /// device belongs to, when it can be told.
+    pub(crate) member_of: Option<String>,
+    /// Why the device's disk can't be formatted because the running system
+    /// depends on it ("holds /boot"); `None` for other devices.
+    pub(crate) system_use: Option<String>,
+}
+
+impl BlockDevice {
+    /// Whether the device is a partition that doesn't start on a 4 KiB
+    /// boundary, the physical sector and flash page size writes get split
+    /// across otherwise. Starts on other multiples of it, such as the SD
+    /// specification's 16 KiB ones, are fine.
+    pub(crate) fn is_misaligned(&self) -> bool {
+        const PHYSICAL_BLOCK: u64 = 4096;
+        self.partition_offset
+            .is_some_and(|off| off % PHYSICAL_BLOCK != 0)
+    }
+
+    pub(crate) fn fingerprint(&self) -> Fingerprint {
+        Fingerprint {
+            serial: self.serial.clone(),
+            wwn: self.wwn.clone(),
+            size: self.size_bytes,
+            model: self.vendor_model.clone(),
+        }
+    }
+}
+
+/// What tells the hardware behind a device path apart, so a stick swapped
+/// after it was selected isn't formatted in its place.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Fingerprint {
+    pub(crate) serial: Option<String>,
+    pub(crate) wwn: Option<String>,
+    pub(crate) size: Option<u64>,
+    pub(crate) model: Option<String>,
+}
+
+impl Fingerprint {
+    /// Fails unless `found`, read from `dev` just now, matches `self`.
+    pub(crate) fn verify(&self, found: &Fingerprint, dev: &str) -> Result<()> {
+        fn change<T: PartialEq>(
+            what: &str,
+            was: &Option<T>,
+            now: &Option<T>,
+            show: impl Fn(&T) -> String,
+        ) -> Option<String> {
+            let show = |v: &Option<T>| v.as_ref().map_or_else(|| "none".to_string(), &show);
+            (was != now).then(|| format!("{what} was {}, now {}", show(was), show(now)))
+        }
+        let changes: Vec<String> = [
+            change("serial", &self.serial, &found.serial, String::clone),
+            change("WWN", &self.wwn, &found.wwn, String::clone),
+            change("size", &self.size, &found.size, |s| format!("{s} bytes")),
+            change("model", &self.model, &found.model, String::clone),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        if !changes.is_empty() {
+            bail!(
+                "{dev} is no longer the selected device ({}); nothing was changed",
+                changes.join("; ")
+            );
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug)]
 pub(crate) struct FormatOptions {
-    pub(crate) fs: String,
+    pub(crate) fs: Filesystem,
     pub(crate) label: Option<String>,
     pub(crate) quick: bool,
     pub(crate) cluster_or_block_size: Option<u64>,
     pub(crate) partition_table: Option<PartitionTable>,
+    pub(crate) partition_type: Option<PartitionType>,
+    pub(crate) partition_flags: PartitionFlags,
+    /// Name of the created GPT partition. `None` uses the label.
+    pub(crate) partition_name: Option<String>,
+    pub(crate) boot_preset: Option<BootPreset>,
+    /// Where created partitions start and end.
+    pub(crate) alignment: Alignment,
+    /// Start of the first created partition in bytes. `None` puts it one
+    /// alignment unit in.
+    pub(crate) partition_offset: Option<u64>,
+    /// Lay the card out per the SD specification ([`sd::SdLayout`]). The
+    /// filesystem and allocation unit must be the ones its layout picks.
+    pub(crate) sd_spec: bool,
+    /// The device as it was when selected. The backend checks it is still the
+    /// same hardware before changing anything.
+    pub(crate) expected: Option<Fingerprint>,
+    pub(crate) advanced: AdvancedOptions,
+}
+
+/// Filesystem-specific mkfs settings. Each field only applies to the
+/// filesystems named in its comment; `Filesystem::validate_advanced` rejects
+/// the rest.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct AdvancedOptions {
+    /// ext2/3/4: percentage of blocks reserved for root.
+    pub(crate) reserved_percent: Option<f64>,
+    /// ext2/3/4: bytes per inode.
+    pub(crate) inode_ratio: Option<u64>,
+    /// ext4: whether to create a journal.
+    pub(crate) journal: Option<bool>,
+    /// ext2/3/4: feature flags for mke2fs, "^name" to disable one.
+    pub(crate) ext_features: Vec<String>,
+    /// vfat: FAT12, FAT16 or FAT32.
+    pub(crate) fat_size: Option<u8>,
+    /// Any filesystem with a settable ID: the UUID or volume serial, in the
+    /// canonical form `Filesystem::parse_volume_id` returns.
+    pub(crate) volume_id: Option<String>,
+    /// btrfs: metadata profile ("single" or "dup").
+    pub(crate) btrfs_metadata: Option<String>,
+    /// btrfs: checksum algorithm.
+    pub(crate) btrfs_checksum: Option<String>,
+    /// ntfs: enable compression.
+    pub(crate) ntfs_compression: bool,
+    /// xfs: reflink support.
+    pub(crate) xfs_reflink: Option<bool>,
+    /// xfs: metadata checksums.
+    pub(crate) xfs_crc: Option<bool>,
+}
+
+impl AdvancedOptions {
+    pub(crate) fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// GPT partition names are 36 UTF-16 code units.
+pub(crate) const GPT_NAME_MAX: usize = 36;
+
+impl FormatOptions {
+    /// The name to give a partition created in `table`: the chosen name or
+    /// the label (cut to fit) on GPT, nothing elsewhere.
+    pub(crate) fn partition_name_for(&self, table: PartitionTable) -> String {
+        if table != PartitionTable::Gpt {
+            return String::new();
+        }
+        if let Some(name) = &self.partition_name {
+            return name.clone();
+        }
+        let mut units = 0;
+        self.label
+            .as_deref()
+            .unwrap_or_default()
+            .chars()
+            .take_while(|c| {
+                units += c.len_utf16();
+                units <= GPT_NAME_MAX
+            })
+            .collect()
+    }
+}
+
+pub(crate) const DEFAULT_ALIGNMENT: u64 = 1024 * 1024;
+
+/// Partition alignment for whole-disk formats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Alignment {
+    Bytes(u64),
+    /// The device's optimal I/O size, or the default when it reports none.
+    OptimalIo,
+}
+
+impl Default for Alignment {
+    fn default() -> Self {
+        Alignment::Bytes(DEFAULT_ALIGNMENT)
+    }
+}
+
+pub(crate) const DEFAULT_ESP_SIZE: u64 = 550 * 1024 * 1024;
+/// The smallest boot partition FAT32 is valid on with 4 KiB clusters, the
+/// minimum Windows uses.
+pub(crate) const MIN_ESP_SIZE: u64 = 260 * 1024 * 1024;
+
+/// Whole-disk layout for boot and firmware-update media: a FAT boot partition
+/// (an ESP on GPT, an active partition on MBR), optionally followed by a data
+/// partition that takes the rest of the disk and the regular format options.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct BootPreset {
+    pub(crate) esp_size: u64,
+    pub(crate) data_partition: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -32,19 +240,205 @@ pub(crate) enum ProgressEvent {
     Completed(Result<(), String>),
 }
 
-#[derive(Clone, Debug)]
+/// Outcome of a filesystem check or repair.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct FsckReport {
+    /// The first check found errors.
+    pub(crate) had_errors: bool,
+    pub(crate) repair_attempted: bool,
+    /// The filesystem is consistent at the end of the operation.
+    pub(crate) consistent: bool,
+}
+
+impl FsckReport {
+    pub(crate) fn summary(&self) -> &'static str {
+        match (self.had_errors, self.repair_attempted, self.consistent) {
+            (false, _, _) => "No errors found",
+            (true, false, _) => "Errors found; run repair to fix them",
+            (true, true, true) => "Errors found and repaired; the filesystem is now consistent",
+            (true, true, false) => "Repair finished but errors remain; back up the data and format",
+        }
+    }
+
+    /// When errors were found: UDisks only passes on pass/fail, so where the
+    /// details are.
+    pub(crate) fn note(&self) -> Option<&'static str> {
+        self.had_errors.then_some(
+            "only the overall result is reported, not what the checker found or fixed; \
+             run the filesystem's fsck tool on the unmounted device for details",
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub(crate) enum PartitionTable {
     Gpt,
     Dos,
+    /// Superfloppy: the filesystem goes directly on the whole device.
+    None,
+}
+
+impl PartitionTable {
+    /// The first byte a partition may start at: the MBR takes the first
+    /// sector, GPT another 33 for its header and entries.
+    pub(crate) fn first_usable_byte(self) -> u64 {
+        match self {
+            PartitionTable::None => 0,
+            PartitionTable::Dos => 512,
+            PartitionTable::Gpt => 34 * 512,
+        }
+    }
+}
+
+impl fmt::Display for PartitionTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PartitionTable::Gpt => "GPT",
+            PartitionTable::Dos => "MBR",
+            PartitionTable::None => "no partition table",
+        })
+    }
+}
+
+/// Type of the partition created for whole-disk formats. `None` in
+/// `FormatOptions` picks one from the filesystem.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum PartitionType {
+    EfiSystem,
+    MicrosoftBasicData,
+    LinuxFilesystem,
+    LinuxSwap,
+    AppleHfsPlus,
+    /// A raw GPT type GUID or MBR type byte (e.g. "0x0c").
+    Custom(String),
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct PartitionFlags {
+    pub(crate) bootable: bool,
+    pub(crate) hidden: bool,
+}
+
+const GPT_ESP: &str = "c12a7328-f81f-11d2-ba4b-00a0c93ec93b";
+const GPT_MS_BASIC_DATA: &str = "ebd0a0a2-b9e5-4433-87c0-68b6b72699c7";
+const GPT_LINUX_FS: &str = "0fc63daf-8483-4772-8e79-3d69d8477de4";
+const GPT_LINUX_SWAP: &str = "0657fd6d-a4ab-43c4-84e5-0933c84b4f4f";
+const GPT_APPLE_HFS: &str = "48465300-0000-11aa-aa11-00306543ecac";
+
+// MBR types that have a "hidden" twin at `type | 0x10`.
+const MBR_HIDEABLE: [u8; 7] = [0x01, 0x04, 0x06, 0x07, 0x0b, 0x0c, 0x0e];
+
+impl PartitionType {
+    /// The type string UDisks expects for `table`: a GUID for GPT, a
+    /// "0xNN" byte for MBR.
+    pub(crate) fn type_id(
+        &self,
+        table: PartitionTable,
+        fs: Filesystem,
+        hidden: bool,
+    ) -> Result<String> {
+        match table {
+            PartitionTable::Gpt => match self {
+                PartitionType::EfiSystem => Ok(GPT_ESP.into()),
+                PartitionType::MicrosoftBasicData => Ok(GPT_MS_BASIC_DATA.into()),
+                PartitionType::LinuxFilesystem => Ok(GPT_LINUX_FS.into()),
+                PartitionType::LinuxSwap => Ok(GPT_LINUX_SWAP.into()),
+                PartitionType::AppleHfsPlus => Ok(GPT_APPLE_HFS.into()),
+                PartitionType::Custom(s) if is_guid(s) => Ok(s.to_ascii_lowercase()),
+                PartitionType::Custom(s) => bail!("GPT partition type must be a GUID, got {s}"),
+            },
+            PartitionTable::None => {
+                bail!("A device without a partition table has no partition type")
+            }
+            PartitionTable::Dos => {
+                let byte = match self {
+                    PartitionType::EfiSystem => 0xef,
+                    PartitionType::MicrosoftBasicData if fs == Filesystem::Vfat => 0x0c,
+                    PartitionType::MicrosoftBasicData => 0x07,
+                    PartitionType::LinuxFilesystem => 0x83,
+                    PartitionType::LinuxSwap => 0x82,
+                    PartitionType::AppleHfsPlus => 0xaf,
+                    PartitionType::Custom(s) => match parse_mbr_type(s) {
+                        Some(b) => b,
+                        None => bail!("MBR partition type must be a byte like 0x0c, got {s}"),
+                    },
+                };
+                let byte = if hidden {
+                    if !MBR_HIDEABLE.contains(&byte) {
+                        bail!("MBR partition type 0x{byte:02x} has no hidden variant");
+                    }
+                    byte | 0x10
+                } else {
+                    byte
+                };
+                Ok(format!("0x{byte:02x}"))
+            }
+        }
+    }
+}
+
+pub(crate) fn is_guid(s: &str) -> bool {
+    let groups: Vec<&str> = s.split('-').collect();
+    groups.len() == 5
+        && groups
+            .iter()
+            .zip([8, 4, 4, 4, 12])
+            .all(|(g, n)| g.len() == n && g.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+pub(crate) fn parse_mbr_type(s: &str) -> Option<u8> {
+    let hex = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X"))?;
+    u8::from_str_radix(hex, 16).ok().filter(|&b| b != 0)
 }
 
 #[async_trait]
 pub(crate) trait Backend: Sync + Send {
-    async fn list_block_devices(&self) -> Result<Vec<BlockDevice>>;
+    /// Removable devices, plus with `include_fixed` fixed drives on external
+    /// buses (USB hard drives, NVMe enclosures) that don't hold the running
+    /// system.
+    async fn list_block_devices(&self, include_fixed: bool) -> Result<Vec<BlockDevice>>;
     async fn format(&self, obj_path: &str, opts: FormatOptions) -> Result<String>;
+    /// The steps `format` would take, after the same checks, without changing
+    /// anything.
+    async fn plan_format(&self, obj_path: &str, opts: &FormatOptions) -> Result<Vec<String>>;
+    async fn set_label(&self, obj_path: &str, label: &str) -> Result<()>;
+    async fn check(&self, obj_path: &str) -> Result<FsckReport>;
+    async fn repair(&self, obj_path: &str) -> Result<FsckReport>;
     async fn cancel(&self, job_id: &str) -> Result<()>;
 }
 
+/// A plan step creating a partition at `offset` bytes spanning `size` (0
+/// for the rest of the disk).
+pub(crate) fn plan_partition(offset: u64, size: u64, type_id: &str, name: &str) -> String {
+    let mut step = format!("Create a partition at byte {offset}");
+    if size == 0 {
+        step.push_str(" filling the rest of the disk");
+    } else {
+        step.push_str(&format!(" of {size} bytes ({})", human_size(size)));
+    }
+    step.push_str(&format!(", type {type_id}"));
+    if !name.is_empty() {
+        step.push_str(&format!(", named \"{name}\""));
+    }
+    step
+}
+
+/// A plan step setting `flags` on a partition in `table`, if any are set.
+pub(crate) fn plan_flags(table: PartitionTable, flags: PartitionFlags) -> Option<String> {
+    let mut set = Vec::new();
+    if flags.bootable {
+        set.push(match table {
+            PartitionTable::Gpt => "legacy BIOS bootable",
+            _ => "bootable",
+        });
+    }
+    // MBR hides partitions through the type byte instead.
+    if flags.hidden && table == PartitionTable::Gpt {
+        set.push("hidden");
+    }
+    (!set.is_empty()).then(|| format!("Mark it {}", set.join(" and ")))
+}
+
 pub(crate) fn human_size(size: u64) -> String {
     const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
     const SI_UNIT_BASE: f64 = 1000.0;
@@ -0,0 +1,270 @@
+//! Card layouts from the SD Association's File System Specification, as the
+//! official SD Formatter writes them: one MBR partition starting a boundary
+//! unit (the card's erase block) in, FAT12/16, FAT32 or exFAT by capacity
+//! class, and the first cluster on a boundary unit.
+
+use super::Filesystem;
+use super::filesystem::{GIB, KIB, MIB, TIB};
+use std::fmt;
+
+const SECTOR: u64 = 512;
+/// Root directory entries on FAT12/16, 32 sectors' worth.
+const ROOT_ENTRIES: u64 = 512;
+/// Most clusters FAT12 can address.
+const FAT12_MAX_CLUSTERS: u64 = 4_084;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SdClass {
+    /// Standard capacity, up to 2 GB.
+    Sdsc,
+    /// High capacity, up to 32 GB.
+    Sdhc,
+    /// Extended capacity, up to 2 TB.
+    Sdxc,
+}
+
+impl fmt::Display for SdClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SdClass::Sdsc => "SDSC",
+            SdClass::Sdhc => "SDHC",
+            SdClass::Sdxc => "SDXC",
+        })
+    }
+}
+
+/// How a card of a given capacity is laid out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct SdLayout {
+    pub(crate) class: SdClass,
+    /// 12, 16 or 32 for FAT; 0 for exFAT.
+    pub(crate) fat_bits: u8,
+    /// Cluster size in bytes.
+    pub(crate) cluster: u64,
+    /// Boundary unit in bytes.
+    pub(crate) boundary: u64,
+}
+
+// (largest capacity, class, FAT bits, cluster, boundary unit), first match
+// wins.
+const LAYOUTS: &[(u64, SdClass, u8, u64, u64)] = &[
+    (8 * MIB, SdClass::Sdsc, 12, 8 * KIB, 16 * KIB),
+    (64 * MIB, SdClass::Sdsc, 12, 16 * KIB, 32 * KIB),
+    (256 * MIB, SdClass::Sdsc, 16, 16 * KIB, 64 * KIB),
+    (GIB, SdClass::Sdsc, 16, 16 * KIB, 128 * KIB),
+    (2 * GIB, SdClass::Sdsc, 16, 32 * KIB, 128 * KIB),
+    (32 * GIB, SdClass::Sdhc, 32, 32 * KIB, 4 * MIB),
+    (512 * GIB, SdClass::Sdxc, 0, 128 * KIB, 16 * MIB),
+    (2 * TIB, SdClass::Sdxc, 0, 256 * KIB, 32 * MIB),
+];
+
+impl SdLayout {
+    /// The layout for a card of `capacity` bytes; `None` past SDXC.
+    pub(crate) fn for_capacity(capacity: u64) -> Option<SdLayout> {
+        let mut layout = LAYOUTS.iter().find(|&&(max, ..)| capacity <= max).map(
+            |&(_, class, fat_bits, cluster, boundary)| SdLayout {
+                class,
+                fat_bits,
+                cluster,
+                boundary,
+            },
+        )?;
+        // A card at the top of a FAT12 range, such as a full 64 MiB one with
+        // 16 KiB clusters, has more clusters than FAT12 can address.
+        if layout.fat_bits == 12 {
+            let offset = layout.partition_offset() / SECTOR;
+            let sectors = capacity.saturating_sub(layout.partition_offset()) / SECTOR;
+            if layout.clusters(offset, sectors) > FAT12_MAX_CLUSTERS {
+                layout.fat_bits = 16;
+            }
+        }
+        Some(layout)
+    }
+
+    pub(crate) fn fs(&self) -> Filesystem {
+        if self.fat_bits == 0 {
+            Filesystem::Exfat
+        } else {
+            Filesystem::Vfat
+        }
+    }
+
+    /// The cluster size in the filesystem's allocation unit (sectors for FAT).
+    pub(crate) fn alloc_unit(&self) -> u64 {
+        match self.fs() {
+            Filesystem::Vfat => self.cluster / SECTOR,
+            _ => self.cluster,
+        }
+    }
+
+    /// Where the partition starts; the first boundary unit holds the MBR.
+    pub(crate) fn partition_offset(&self) -> u64 {
+        self.boundary
+    }
+
+    /// MBR type byte of a partition of `size` bytes.
+    pub(crate) fn mbr_type(&self, size: u64) -> &'static str {
+        match self.fat_bits {
+            12 => "0x01",
+            16 if size < 32 * MIB => "0x04",
+            16 => "0x06",
+            32 => "0x0c",
+            _ => "0x07",
+        }
+    }
+
+    /// mkfs arguments for a partition of `size` bytes starting `offset` bytes
+    /// into the card, putting the first cluster on a boundary unit.
+    pub(crate) fn mkfs_args(&self, offset: u64, size: u64) -> Vec<String> {
+        if self.fat_bits == 0 {
+            return vec![
+                format!("-c{}", self.cluster),
+                format!("-b{}", self.boundary),
+            ];
+        }
+        let mut args = vec![
+            format!("-F{}", self.fat_bits),
+            format!("-s{}", self.cluster / SECTOR),
+            format!("-R{}", self.fat_reserved(offset / SECTOR, size / SECTOR)),
+            // mkfs.fat's own alignment would move the data area again.
+            "-a".to_string(),
+        ];
+        if self.fat_bits != 32 {
+            args.push(format!("-r{ROOT_ENTRIES}"));
+        }
+        args
+    }
+
+    /// Reserved sectors that push the data area onto a boundary unit. Growing
+    /// the reserved area can only shrink the FATs, so this settles quickly.
+    fn fat_reserved(&self, offset: u64, sectors: u64) -> u64 {
+        let boundary = self.boundary / SECTOR;
+        let root = self.root_dir_sectors();
+        let mut reserved = if self.fat_bits == 32 { 32 } else { 1 };
+        loop {
+            let fat = self.fat_length(sectors.saturating_sub(reserved + root));
+            let data_start = offset + reserved + 2 * fat + root;
+            let pad = (boundary - data_start % boundary) % boundary;
+            if pad == 0 {
+                return reserved;
+            }
+            reserved += pad;
+        }
+    }
+
+    /// Sectors of the FAT12/16 root directory; FAT32 keeps it in the data
+    /// area.
+    fn root_dir_sectors(&self) -> u64 {
+        if self.fat_bits == 32 {
+            0
+        } else {
+            ROOT_ENTRIES * 32 / SECTOR
+        }
+    }
+
+    /// Clusters on a FAT partition of `sectors` starting `offset` sectors in.
+    fn clusters(&self, offset: u64, sectors: u64) -> u64 {
+        let root = self.root_dir_sectors();
+        let reserved = self.fat_reserved(offset, sectors);
+        let fat = self.fat_length(sectors.saturating_sub(reserved + root));
+        sectors.saturating_sub(reserved + 2 * fat + root) / (self.cluster / SECTOR)
+    }
+
+    /// Sectors per FAT for `data` sectors after the reserved area and root
+    /// directory, worked out the way mkfs.fat does for two FATs.
+    fn fat_length(&self, data: u64) -> u64 {
+        let spc = self.cluster / SECTOR;
+        match self.fat_bits {
+            12 => {
+                let clusters = 2 * (data * SECTOR + 6) / (2 * spc * SECTOR + 6);
+                ((clusters + 2) * 3).div_ceil(2).div_ceil(SECTOR)
+            }
+            16 => {
+                let clusters = (data * SECTOR + 8) / (spc * SECTOR + 4);
+                ((clusters + 2) * 2).div_ceil(SECTOR)
+            }
+            _ => {
+                let clusters = (data * SECTOR + 16) / (spc * SECTOR + 8);
+                ((clusters + 2) * 4).div_ceil(SECTOR)
+            }
+        }
+    }
+}
+
+impl fmt::Display for SdLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fs = match self.fat_bits {
+            0 => "exFAT".to_string(),
+            bits => format!("FAT{bits}"),
+        };
+        write!(
+            f,
+            "{} card, {fs}, {} clusters, {} boundary units",
+            self.class,
+            binary_size(self.cluster),
+            binary_size(self.boundary)
+        )
+    }
+}
+
+fn binary_size(bytes: u64) -> String {
+    if bytes >= MIB {
+        format!("{} MiB", bytes / MIB)
+    } else {
+        format!("{} KiB", bytes / KIB)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sector the data area starts on for a card of `capacity` bytes.
+    fn data_start(layout: &SdLayout, capacity: u64) -> u64 {
+        let offset = layout.partition_offset() / SECTOR;
+        let sectors = (capacity - layout.partition_offset()) / SECTOR;
+        let root = layout.root_dir_sectors();
+        let reserved = layout.fat_reserved(offset, sectors);
+        let fat = layout.fat_length(sectors - reserved - root);
+        offset + reserved + 2 * fat + root
+    }
+
+    fn assert_aligned(capacity: u64, fat_bits: u8) {
+        let layout = SdLayout::for_capacity(capacity).unwrap();
+        assert_eq!(layout.fat_bits, fat_bits);
+        assert_eq!(
+            data_start(&layout, capacity) % (layout.boundary / SECTOR),
+            0
+        );
+    }
+
+    #[test]
+    fn fat12_data_area_on_boundary() {
+        assert_aligned(32 * MIB, 12);
+    }
+
+    #[test]
+    fn fat16_data_area_on_boundary() {
+        assert_aligned(GIB, 16);
+        assert_aligned(2_000_000_000, 16);
+    }
+
+    #[test]
+    fn fat32_data_area_on_boundary() {
+        assert_aligned(16 * GIB, 32);
+        assert_aligned(31_900_000_000, 32);
+    }
+
+    #[test]
+    fn full_64_mib_card_is_too_big_for_fat12() {
+        let card = 64 * MIB;
+        let layout = SdLayout::for_capacity(card).unwrap();
+        let offset = layout.partition_offset() / SECTOR;
+        let sectors = (card - layout.partition_offset()) / SECTOR;
+        assert_eq!(layout.fat_bits, 16);
+        assert!(layout.clusters(offset, sectors) > FAT12_MAX_CLUSTERS);
+        assert_eq!(data_start(&layout, card) % (layout.boundary / SECTOR), 0);
+        // A "64 MB" card in decimal megabytes still fits.
+        assert_aligned(64_000_000, 12);
+    }
+}
@@ -0,0 +1,521 @@
+//! The steps of a format, worked out once from the options and the device.
+//! Backends run them in `format` and describe them in `plan_format`, so a
+//! plan shows exactly what a real run does.
+
+use super::sd::SdLayout;
+use super::{
+    AdvancedOptions, Alignment, BootPreset, DEFAULT_ALIGNMENT, Filesystem, FormatOptions,
+    PartitionFlags, PartitionTable, PartitionType, human_size, plan_flags, plan_partition,
+};
+use anyhow::{Result, bail};
+
+const ESP_LABEL: &str = "EFI";
+const ESP_NAME: &str = "EFI system partition";
+
+/// A whole disk being formatted.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Geometry {
+    /// Size in bytes.
+    pub(crate) capacity: u64,
+    /// Boundary created partitions start on, in bytes.
+    pub(crate) align: u64,
+}
+
+impl Geometry {
+    /// The geometry of a disk of `capacity` bytes, resolving the optimal I/O
+    /// size to `optimal` (the default when the device reports none).
+    pub(crate) fn new(capacity: u64, alignment: Alignment, optimal: Option<u64>) -> Geometry {
+        let align = match alignment {
+            Alignment::Bytes(bytes) => bytes,
+            Alignment::OptimalIo => optimal.unwrap_or(DEFAULT_ALIGNMENT),
+        };
+        Geometry { capacity, align }
+    }
+}
+
+/// What a [`Step::Format`] acts on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Target {
+    /// The device itself: the partition being formatted, or a whole disk
+    /// without a partition table.
+    Device,
+    /// The `n`th partition created by earlier steps.
+    Partition(usize),
+}
+
+/// Where a [`Step::CreatePartition`] puts the partition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Start {
+    At(u64),
+    /// The first aligned byte after the previous partition.
+    AfterPrevious,
+}
+
+/// The filesystem a [`Step::Format`] creates.
+#[derive(Clone, Debug)]
+pub(crate) struct Volume {
+    pub(crate) opts: FormatOptions,
+    /// The SD layout, whose mkfs arguments depend on where the partition
+    /// ended up.
+    pub(crate) sd: Option<SdLayout>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum Step {
+    /// Unmount the device, and with `partitions` everything on it.
+    Unmount { partitions: bool },
+    /// Wipe the device and write an empty partition table.
+    CreateTable(PartitionTable),
+    /// Create a partition `size` bytes long (0 fills the disk) and set its
+    /// flags.
+    CreatePartition {
+        start: Start,
+        size: u64,
+        type_id: String,
+        name: String,
+        flags: PartitionFlags,
+    },
+    /// Format `target`, called `what` in progress messages and plans.
+    Format {
+        target: Target,
+        volume: Box<Volume>,
+        what: &'static str,
+    },
+}
+
+impl Step {
+    /// The progress message shown when the step starts, if any.
+    pub(crate) fn message(&self) -> Option<String> {
+        match self {
+            Step::CreateTable(_) => Some("Creating partition table...".to_string()),
+            Step::Format { what, .. } => Some(format!("Formatting {what}...")),
+            Step::Unmount { .. } | Step::CreatePartition { .. } => None,
+        }
+    }
+}
+
+/// The steps formatting a device with `opts` takes. `disk` is `None` when
+/// the device is a partition.
+pub(crate) fn format_steps(opts: &FormatOptions, disk: Option<Geometry>) -> Result<Vec<Step>> {
+    let data = Box::new(Volume {
+        opts: opts.clone(),
+        sd: None,
+    });
+    let Some(disk) = disk else {
+        return Ok(vec![
+            Step::Unmount { partitions: false },
+            Step::Format {
+                target: Target::Device,
+                volume: data,
+                what: "partition",
+            },
+        ]);
+    };
+    let mut steps = vec![Step::Unmount { partitions: true }];
+    if opts.sd_spec {
+        let layout = sd_layout(disk.capacity, opts)?;
+        let offset = layout.partition_offset();
+        steps.extend([
+            Step::CreateTable(PartitionTable::Dos),
+            Step::CreatePartition {
+                start: Start::At(offset),
+                size: 0,
+                type_id: layout.mbr_type(disk.capacity - offset).to_string(),
+                name: String::new(),
+                flags: PartitionFlags::default(),
+            },
+            Step::Format {
+                target: Target::Partition(0),
+                volume: Box::new(Volume {
+                    sd: Some(layout),
+                    ..*data
+                }),
+                what: "card",
+            },
+        ]);
+        return Ok(steps);
+    }
+    let table = opts.partition_table.unwrap_or(PartitionTable::Gpt);
+    if table == PartitionTable::None && opts.boot_preset.is_none() {
+        steps.push(Step::Format {
+            target: Target::Device,
+            volume: data,
+            what: "device",
+        });
+        return Ok(steps);
+    }
+    // By default the first partition starts on the first aligned byte past
+    // the table.
+    let min = table.first_usable_byte();
+    let first = opts
+        .partition_offset
+        .unwrap_or_else(|| min.next_multiple_of(disk.align));
+    if first < min {
+        bail!("The first partition must start at least {min} bytes in on {table}");
+    }
+    let start = Start::At(first);
+    let data_partition = |start| -> Result<Step> {
+        let type_id = opts
+            .partition_type
+            .clone()
+            .unwrap_or_else(|| opts.fs.partition_type())
+            .type_id(table, opts.fs, opts.partition_flags.hidden)?;
+        Ok(Step::CreatePartition {
+            start,
+            size: 0,
+            type_id,
+            name: opts.partition_name_for(table),
+            flags: opts.partition_flags,
+        })
+    };
+    let Some(preset) = opts.boot_preset else {
+        steps.extend([
+            Step::CreateTable(table),
+            data_partition(start)?,
+            Step::Format {
+                target: Target::Partition(0),
+                volume: data,
+                what: "partition",
+            },
+        ]);
+        return Ok(steps);
+    };
+    if preset.data_partition {
+        // The data partition starts on the first aligned byte past the ESP.
+        let data_start = first
+            .checked_add(preset.esp_size)
+            .and_then(|end| end.checked_next_multiple_of(disk.align));
+        if data_start.is_none_or(|s| s.saturating_add(disk.align) > disk.capacity) {
+            bail!(
+                "A {} boot partition leaves no room for a data partition on this {} disk",
+                human_size(preset.esp_size),
+                human_size(disk.capacity)
+            );
+        }
+    }
+    let (boot, boot_volume) = boot_partition(opts, preset, table, start)?;
+    steps.extend([Step::CreateTable(table), boot]);
+    if !preset.data_partition {
+        steps.push(Step::Format {
+            target: Target::Partition(0),
+            volume: boot_volume,
+            what: "boot partition",
+        });
+        return Ok(steps);
+    }
+    steps.extend([
+        data_partition(Start::AfterPrevious)?,
+        Step::Format {
+            target: Target::Partition(0),
+            volume: boot_volume,
+            what: "boot partition",
+        },
+        Step::Format {
+            target: Target::Partition(1),
+            volume: data,
+            what: "data partition",
+        },
+    ]);
+    Ok(steps)
+}
+
+/// The boot partition of `preset` on a new `table`, and its filesystem.
+fn boot_partition(
+    opts: &FormatOptions,
+    preset: BootPreset,
+    table: PartitionTable,
+    start: Start,
+) -> Result<(Step, Box<Volume>)> {
+    // UEFI firmware looks for an ESP on GPT; on MBR it (and BIOS) wants an
+    // active FAT partition.
+    let (boot_type, flags) = match table {
+        PartitionTable::None => bail!("The bootable (UEFI) preset needs a partition table"),
+        PartitionTable::Gpt => (PartitionType::EfiSystem, PartitionFlags::default()),
+        PartitionTable::Dos => (
+            PartitionType::MicrosoftBasicData,
+            PartitionFlags {
+                bootable: true,
+                hidden: false,
+            },
+        ),
+    };
+    // Left to itself mkfs.fat picks FAT16 for small partitions, which some
+    // firmware won't boot from. Without a data partition the options
+    // (vfat, checked by the caller) are for the boot partition itself.
+    let mut boot_opts = if preset.data_partition {
+        FormatOptions {
+            fs: Filesystem::Vfat,
+            label: Some(ESP_LABEL.to_string()),
+            cluster_or_block_size: None,
+            advanced: AdvancedOptions::default(),
+            ..opts.clone()
+        }
+    } else {
+        if opts.advanced.fat_size.is_some_and(|bits| bits != 32) {
+            bail!("The bootable (UEFI) preset formats the boot partition as FAT32");
+        }
+        opts.clone()
+    };
+    boot_opts.advanced.fat_size = Some(32);
+    let step = Step::CreatePartition {
+        start,
+        size: if preset.data_partition {
+            preset.esp_size
+        } else {
+            0
+        },
+        type_id: boot_type.type_id(table, Filesystem::Vfat, false)?,
+        name: match table {
+            PartitionTable::Gpt if preset.data_partition => ESP_NAME.to_string(),
+            _ => opts.partition_name_for(table),
+        },
+        flags,
+    };
+    let volume = Box::new(Volume {
+        opts: boot_opts,
+        sd: None,
+    });
+    Ok((step, volume))
+}
+
+/// The SD layout for a card of `capacity` bytes, checked against `opts`.
+fn sd_layout(capacity: u64, opts: &FormatOptions) -> Result<SdLayout> {
+    let Some(layout) = SdLayout::for_capacity(capacity) else {
+        bail!("The card is larger than the SD specification's 2 TB");
+    };
+    if layout.fs() != opts.fs {
+        bail!(
+            "The SD specification puts {} on this card, not {}",
+            layout.fs(),
+            opts.fs
+        );
+    }
+    if opts.advanced.fat_size.is_some() {
+        bail!("The SD specification picks the FAT type");
+    }
+    Ok(layout)
+}
+
+/// Describes `steps` on device `dev` for a plan. `describe` gives the
+/// filesystem a volume gets on a partition at `offset` bytes spanning
+/// `size`.
+pub(crate) fn plan_steps(
+    steps: &[Step],
+    dev: &str,
+    disk: Option<Geometry>,
+    describe: impl Fn(&Volume, u64, u64) -> String,
+) -> Vec<String> {
+    let capacity = disk.map_or(0, |d| d.capacity);
+    let align = disk.map_or(DEFAULT_ALIGNMENT, |d| d.align);
+    let created = steps
+        .iter()
+        .filter(|s| matches!(s, Step::CreatePartition { .. }))
+        .count();
+    let mut table = PartitionTable::None;
+    // (offset, size) of each created partition.
+    let mut parts: Vec<(u64, u64)> = Vec::new();
+    let mut plan = Vec::new();
+    for step in steps {
+        match step {
+            Step::Unmount { partitions: false } => plan.push(format!("Unmount {dev} if mounted")),
+            Step::Unmount { partitions: true } => {
+                plan.push(format!("Unmount {dev} and its partitions"))
+            }
+            Step::CreateTable(t) => {
+                table = *t;
+                plan.push(format!("Wipe {dev} and write an empty {t} partition table"));
+            }
+            Step::CreatePartition {
+                start,
+                size,
+                type_id,
+                name,
+                flags,
+            } => {
+                let offset = match start {
+                    Start::At(offset) => *offset,
+                    Start::AfterPrevious => parts
+                        .last()
+                        .map_or(0, |(o, s)| (o + s).next_multiple_of(align)),
+                };
+                plan.push(plan_partition(offset, *size, type_id, name));
+                plan.extend(plan_flags(table, *flags));
+                let size = if *size == 0 {
+                    capacity.saturating_sub(offset)
+                } else {
+                    *size
+                };
+                parts.push((offset, size));
+            }
+            Step::Format {
+                target,
+                volume,
+                what,
+            } => match target {
+                Target::Device if disk.is_some() => plan.push(format!(
+                    "Format {dev} as {}, without a partition table",
+                    describe(volume, 0, capacity)
+                )),
+                Target::Device => plan.push(format!("Format {dev} as {}", describe(volume, 0, 0))),
+                Target::Partition(n) => {
+                    let (offset, size) = parts.get(*n).copied().unwrap_or_default();
+                    let desc = describe(volume, offset, size);
+                    if created > 1 {
+                        plan.push(format!("Format the {what} as {desc}"));
+                    } else {
+                        plan.push(format!("Format it as {desc}"));
+                    }
+                }
+            },
+        }
+    }
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::filesystem::GIB;
+
+    fn opts(fs: Filesystem) -> FormatOptions {
+        FormatOptions {
+            fs,
+            label: Some("DATA".into()),
+            quick: true,
+            cluster_or_block_size: None,
+            partition_table: None,
+            partition_type: None,
+            partition_flags: PartitionFlags::default(),
+            partition_name: None,
+            boot_preset: None,
+            alignment: Alignment::default(),
+            partition_offset: None,
+            sd_spec: false,
+            expected: None,
+            advanced: AdvancedOptions::default(),
+        }
+    }
+
+    fn disk(capacity: u64) -> Option<Geometry> {
+        Some(Geometry::new(capacity, Alignment::default(), None))
+    }
+
+    fn plan(opts: &FormatOptions, disk: Option<Geometry>) -> Vec<String> {
+        let steps = format_steps(opts, disk).unwrap();
+        plan_steps(&steps, "/dev/sdx", disk, |v, _, _| v.opts.fs.to_string())
+    }
+
+    #[test]
+    fn partition_is_formatted_in_place() {
+        assert_eq!(
+            plan(&opts(Filesystem::Ext4), None),
+            ["Unmount /dev/sdx if mounted", "Format /dev/sdx as ext4"]
+        );
+    }
+
+    #[test]
+    fn gpt_start_clears_the_entry_array() {
+        let o = opts(Filesystem::Ext4);
+        let small = Some(Geometry::new(8 * GIB, Alignment::Bytes(4096), None));
+        let plan = plan(&o, small);
+        // 34 sectors rounded up to 4 KiB.
+        assert!(plan[2].starts_with("Create a partition at byte 20480 "));
+        let mut o = o;
+        o.partition_table = Some(PartitionTable::Dos);
+        let plan = self::plan(&o, small);
+        assert!(plan[2].starts_with("Create a partition at byte 4096 "));
+        o.partition_table = Some(PartitionTable::Gpt);
+        o.partition_offset = Some(4096);
+        assert!(format_steps(&o, small).is_err());
+    }
+
+    #[test]
+    fn partition_type_is_honored() {
+        let mut o = opts(Filesystem::Ext4);
+        o.partition_table = Some(PartitionTable::Dos);
+        o.partition_type = Some(PartitionType::Custom("0x0b".into()));
+        let steps = format_steps(&o, disk(8 * GIB)).unwrap();
+        let type_id = steps.iter().find_map(|s| match s {
+            Step::CreatePartition { type_id, .. } => Some(type_id.as_str()),
+            _ => None,
+        });
+        assert_eq!(type_id, Some("0x0b"));
+    }
+
+    #[test]
+    fn superfloppy_has_no_table() {
+        let mut o = opts(Filesystem::Vfat);
+        o.partition_table = Some(PartitionTable::None);
+        let steps = format_steps(&o, disk(8 * GIB)).unwrap();
+        assert!(!steps.iter().any(|s| matches!(s, Step::CreateTable(_))));
+        assert!(matches!(
+            steps.last(),
+            Some(Step::Format {
+                target: Target::Device,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn data_partition_follows_esp_aligned() {
+        let mut o = opts(Filesystem::Ext4);
+        o.boot_preset = Some(BootPreset {
+            esp_size: 300 * 1024 * 1024 + 512,
+            data_partition: true,
+        });
+        let plan = plan(&o, disk(8 * GIB));
+        let data_start = (1024 * 1024 + 300 * 1024 * 1024 + 512_u64).next_multiple_of(1024 * 1024);
+        assert!(
+            plan.iter()
+                .any(|l| l.starts_with(&format!("Create a partition at byte {data_start} ")))
+        );
+        assert_eq!(plan[plan.len() - 2], "Format the boot partition as vfat");
+        assert_eq!(plan[plan.len() - 1], "Format the data partition as ext4");
+    }
+
+    #[test]
+    fn esp_must_leave_room_for_data() {
+        let mut o = opts(Filesystem::Ext4);
+        o.boot_preset = Some(BootPreset {
+            esp_size: GIB,
+            data_partition: true,
+        });
+        assert!(format_steps(&o, disk(GIB)).is_err());
+        assert!(format_steps(&o, disk(2 * GIB)).is_ok());
+        o.boot_preset = Some(BootPreset {
+            esp_size: u64::MAX,
+            data_partition: true,
+        });
+        assert!(format_steps(&o, disk(2 * GIB)).is_err());
+    }
+
+    #[test]
+    fn esp_is_fat32() {
+        let mut o = opts(Filesystem::Vfat);
+        o.boot_preset = Some(BootPreset {
+            esp_size: 0,
+            data_partition: false,
+        });
+        let steps = format_steps(&o, disk(8 * GIB)).unwrap();
+        let Some(Step::Format { volume, .. }) = steps.last() else {
+            panic!("no format step");
+        };
+        assert_eq!(volume.opts.advanced.fat_size, Some(32));
+        o.advanced.fat_size = Some(16);
+        assert!(format_steps(&o, disk(8 * GIB)).is_err());
+    }
+
+    #[test]
+    fn sd_layout_must_match_filesystem() {
+        let mut o = opts(Filesystem::Ext4);
+        o.sd_spec = true;
+        assert!(format_steps(&o, disk(16 * GIB)).is_err());
+        o.fs = Filesystem::Vfat;
+        let steps = format_steps(&o, disk(16 * GIB)).unwrap();
+        assert!(matches!(
+            steps.last(),
+            Some(Step::Format { volume, .. }) if volume.sd.is_some()
+        ));
+    }
+}
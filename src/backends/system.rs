@@ -0,0 +1,291 @@
+//! Which disks the running system depends on, worked out from /proc and
+//! /sys: what holds `/`, `/boot` and live media, active swap, members of
+//! active device-mapper and MD devices, imported ZFS pools, and the disk
+//! diskfmt itself runs from.
+
+use std::collections::HashMap;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+const SYS_BLOCK: &str = "/sys/class/block";
+
+/// Mount points of the system and of the media live systems boot from.
+const SYSTEM_MOUNTS: [&str; 8] = [
+    "/",
+    "/boot",
+    "/usr",
+    "/run/live/medium",
+    "/lib/live/mount/medium",
+    "/run/initramfs/live",
+    "/run/archiso/bootmnt",
+    "/cdrom",
+];
+
+/// Kernel names of the disks the running system depends on (`sda`,
+/// `nvme0n1`), each with why ("holds /boot").
+pub(crate) fn system_disks() -> HashMap<String, String> {
+    let mut disks = HashMap::new();
+    let mut add = |name: &str, why: String| {
+        for disk in base_disks(name) {
+            disks.entry(disk).or_insert_with(|| why.clone());
+        }
+    };
+    for mount in read_mounts() {
+        let target = &mount.target;
+        if (SYSTEM_MOUNTS.contains(&target.as_str()) || target.starts_with("/boot/"))
+            && let Some(name) = mount_device(&mount)
+        {
+            add(&name, format!("holds {target}"));
+        }
+    }
+    for dev in swaps() {
+        add(&dev, "holds active swap".to_string());
+    }
+    for name in dir_names(Path::new(SYS_BLOCK)) {
+        if !dir_names(&Path::new(SYS_BLOCK).join(&name).join("holders")).is_empty() {
+            add(&name, "is in use by LVM, RAID or device-mapper".to_string());
+        }
+    }
+    // An AppImage runs from a FUSE mount; the image itself is on a disk.
+    let exe = env::var_os("APPIMAGE")
+        .map(PathBuf::from)
+        .or_else(|| env::current_exe().ok());
+    if let Some(name) = exe.as_deref().and_then(block_name_of) {
+        add(&name, "holds the running diskfmt".to_string());
+    }
+    disks
+}
+
+/// Whether the ZFS pool `pool` is imported.
+pub(crate) fn zfs_pool_imported(pool: &str) -> bool {
+    !pool.is_empty() && Path::new("/proc/spl/kstat/zfs").join(pool).is_dir()
+}
+
+/// Kernel name of the disk `dev_path` (a device node) is on.
+pub(crate) fn disk_of(dev_path: &str) -> Option<String> {
+    let name = block_name_of(Path::new(dev_path))?;
+    let sys = Path::new(SYS_BLOCK).join(&name);
+    if !sys.join("partition").exists() {
+        return Some(name);
+    }
+    let parent = fs::canonicalize(sys).ok()?;
+    Some(parent.parent()?.file_name()?.to_string_lossy().into_owned())
+}
+
+/// The disks under block device `name`: itself, the disk a partition is on,
+/// or the disks behind a device-mapper, MD or loop device.
+fn base_disks(name: &str) -> Vec<String> {
+    let sys = Path::new(SYS_BLOCK).join(name);
+    let slaves = dir_names(&sys.join("slaves"));
+    if !slaves.is_empty() {
+        return slaves.iter().flat_map(|s| base_disks(s)).collect();
+    }
+    if let Ok(backing) = fs::read_to_string(sys.join("loop/backing_file")) {
+        return match block_name_of(Path::new(backing.trim())) {
+            Some(n) => base_disks(&n),
+            None => Vec::new(),
+        };
+    }
+    disk_of(&format!("/dev/{name}")).into_iter().collect()
+}
+
+/// A line of /proc/self/mountinfo.
+#[derive(Debug, PartialEq)]
+struct Mount {
+    /// `major:minor` as the kernel reports it; anonymous (`0:NN`) on btrfs.
+    dev: String,
+    target: String,
+    /// What was mounted, e.g. `/dev/nvme0n1p3`.
+    source: String,
+}
+
+fn read_mounts() -> Vec<Mount> {
+    fs::read_to_string("/proc/self/mountinfo")
+        .map(|info| parse_mountinfo(&info))
+        .unwrap_or_default()
+}
+
+/// Parses mountinfo lines: `id parent major:minor root target options
+/// [optional fields] - fstype source super-options`.
+fn parse_mountinfo(info: &str) -> Vec<Mount> {
+    info.lines()
+        .filter_map(|line| {
+            let (head, tail) = line.split_once(" - ")?;
+            let mut fields = head.split_whitespace().skip(2);
+            let dev = fields.next()?;
+            let target = fields.nth(1)?;
+            let source = tail.split_whitespace().nth(1)?;
+            Some(Mount {
+                dev: dev.to_string(),
+                target: unescape_mount(target),
+                source: unescape_mount(source),
+            })
+        })
+        .collect()
+}
+
+/// Kernel name of the block device behind `mount`: its source when that is
+/// a device node, since btrfs reports anonymous device numbers, otherwise
+/// its device number.
+fn mount_device(mount: &Mount) -> Option<String> {
+    if mount.source.starts_with("/dev/")
+        && let Some(name) = node_name(Path::new(&mount.source))
+    {
+        return Some(name);
+    }
+    name_of_dev_number(&mount.dev)
+}
+
+/// The mount holding `path` (absolute, without symlinks): the last one on
+/// the longest matching mount point, as later mounts hide earlier ones.
+fn containing_mount<'a>(path: &Path, mounts: &'a [Mount]) -> Option<&'a Mount> {
+    mounts
+        .iter()
+        .filter(|m| path.starts_with(&m.target))
+        .max_by_key(|m| m.target.len())
+}
+
+/// Kernel names of active swap partitions, and of the devices holding swap
+/// files.
+fn swaps() -> Vec<String> {
+    let Ok(swaps) = fs::read_to_string("/proc/swaps") else {
+        return Vec::new();
+    };
+    swaps
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().next())
+        .filter_map(|path| block_name_of(Path::new(&unescape_mount(path))))
+        .collect()
+}
+
+/// Kernel name of the block device `path` is (a device node) or is on (any
+/// other file). Files are traced through the mount holding them, since
+/// their `st_dev` is anonymous on btrfs.
+fn block_name_of(path: &Path) -> Option<String> {
+    if let Some(name) = node_name(path) {
+        return Some(name);
+    }
+    let path = fs::canonicalize(path).ok()?;
+    mount_device(containing_mount(&path, &read_mounts())?)
+}
+
+/// Kernel name of the block device node `path`.
+fn node_name(path: &Path) -> Option<String> {
+    let meta = fs::metadata(path).ok()?;
+    if !meta.file_type().is_block_device() {
+        return None;
+    }
+    name_of_dev_number(&dev_number(meta.rdev()))
+}
+
+/// `major:minor` of a `dev_t`, as glibc's `major()` and `minor()` split it.
+fn dev_number(dev: u64) -> String {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & 0xffff_f000);
+    let minor = (dev & 0xff) | ((dev >> 12) & 0xffff_ff00);
+    format!("{major}:{minor}")
+}
+
+/// Kernel name of the block device numbered `major:minor`; `None` for
+/// virtual filesystems.
+fn name_of_dev_number(dev: &str) -> Option<String> {
+    let path = fs::canonicalize(Path::new("/sys/dev/block").join(dev)).ok()?;
+    Some(path.file_name()?.to_string_lossy().into_owned())
+}
+
+fn dir_names(dir: &Path) -> Vec<String> {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.file_name().to_string_lossy().into_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Undoes the octal escapes (`\040` for a space) of /proc mount tables.
+fn unescape_mount(s: &str) -> String {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(i) = rest.find('\\') {
+        out.push_str(&rest[..i]);
+        match rest
+            .get(i + 1..i + 4)
+            .and_then(|oct| u8::from_str_radix(oct, 8).ok())
+        {
+            Some(b) => {
+                out.push(b as char);
+                rest = &rest[i + 4..];
+            }
+            None => {
+                out.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &str = "\
+22 1 0:21 / /proc rw,nosuid,nodev,noexec,relatime shared:12 - proc proc rw
+29 1 0:35 /root / rw,relatime shared:1 - btrfs /dev/nvme0n1p3 rw,ssd,subvol=/root
+61 29 259:1 / /boot/efi rw,relatime shared:31 master:2 - vfat /dev/nvme0n1p1 rw,fmask=0077
+75 29 8:17 / /run/media/me/My\\040Stick rw,nosuid - exfat /dev/sdb1 rw
+";
+
+    #[test]
+    fn parses_mountinfo_source_and_target() {
+        let mounts = parse_mountinfo(MOUNTINFO);
+        assert_eq!(mounts.len(), 4);
+        assert_eq!(
+            mounts[1],
+            Mount {
+                dev: "0:35".into(),
+                target: "/".into(),
+                source: "/dev/nvme0n1p3".into(),
+            }
+        );
+        // Optional fields before the separator don't shift the source.
+        assert_eq!(mounts[2].source, "/dev/nvme0n1p1");
+        assert_eq!(mounts[3].target, "/run/media/me/My Stick");
+    }
+
+    #[test]
+    fn skips_malformed_mountinfo_lines() {
+        assert!(parse_mountinfo("29 1 0:35 / /\n\n").is_empty());
+    }
+
+    #[test]
+    fn finds_the_innermost_mount() {
+        let mounts = parse_mountinfo(MOUNTINFO);
+        let mount = |p: &str| containing_mount(Path::new(p), &mounts).map(|m| m.source.as_str());
+        assert_eq!(mount("/usr/bin/diskfmt"), Some("/dev/nvme0n1p3"));
+        assert_eq!(mount("/boot/efi/EFI/BOOT"), Some("/dev/nvme0n1p1"));
+        assert_eq!(mount("/boot/efiboot"), Some("/dev/nvme0n1p3"));
+        assert_eq!(mount("/run/media/me/My Stick/a"), Some("/dev/sdb1"));
+    }
+
+    #[test]
+    fn unescapes_mount_paths() {
+        assert_eq!(unescape_mount("/media/My\\040Stick"), "/media/My Stick");
+        assert_eq!(unescape_mount("/a\\011b\\134c"), "/a\tb\\c");
+        assert_eq!(unescape_mount("/odd\\x"), "/odd\\x");
+        assert_eq!(unescape_mount("/trailing\\"), "/trailing\\");
+    }
+
+    #[test]
+    fn decodes_dev_numbers() {
+        assert_eq!(dev_number(0x801), "8:1");
+        assert_eq!(dev_number(0x10301), "259:1");
+        // Minors over 255 and majors over 4095 use the high bits.
+        assert_eq!(dev_number(0x10_082c), "8:300");
+        assert_eq!(dev_number((0x1000 << 32) | (0x234 << 8)), "4660:0");
+    }
+}
@@ -1,26 +1,298 @@
-use super::{Backend, BlockDevice, FormatOptions, ProgressEvent};
+use super::steps::{Geometry, Start, Step, Target, Volume, format_steps, plan_steps};
+use super::system;
+use super::{
+    Alignment, Backend, BlockDevice, DEFAULT_ALIGNMENT, Filesystem, Fingerprint, FormatOptions,
+    FsckReport, PartitionFlags, PartitionTable, ProgressEvent,
+};
 use crate::common::{Msg, UiSender};
+use crate::config::SafetyConfig;
 use anyhow::{Result, bail};
 use async_trait::async_trait;
 use fudisks as ud;
 use futures_util::StreamExt;
+use std::collections::HashMap;
+use std::future::Future;
+use std::process::{Command, Stdio};
+use tokio::time::{Duration, Instant, interval, sleep};
+use ud2::zbus::zvariant::Value;
+use udisks2 as ud2;
+
+const IFACE_PARTITION: &str = "org.freedesktop.UDisks2.Partition";
+const IFACE_PARTITION_TABLE: &str = "org.freedesktop.UDisks2.PartitionTable";
+const IFACE_FILESYSTEM: &str = "org.freedesktop.UDisks2.Filesystem";
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(10);
+const SETTLE_POLL: Duration = Duration::from_millis(100);
+const SECTOR: u64 = 512;
+const JOB_POLL: Duration = Duration::from_millis(200);
+/// Buses fixed drives can be unplugged from.
+const EXTERNAL_BUSES: [&str; 3] = ["usb", "ieee1394", "sdio"];
+
+/// What a format hands to UDisks: fudisks covers the common filesystems, the
+/// rest and anything needing extra mkfs arguments go to `Block.Format`
+/// directly.
+enum FormatRequest {
+    Fudisks(ud::FormatOptions),
+    Raw {
+        fs: Filesystem,
+        label: Option<String>,
+        mkfs_args: Vec<String>,
+        quick: bool,
+        /// Volume ID mkfs can't set, applied once the filesystem exists.
+        volume_id: Option<String>,
+    },
+}
+
+impl FormatRequest {
+    /// Type and options for a blocking `Block.Format` call.
+    fn block_format_args(&self) -> (&str, HashMap<&'static str, Value<'_>>) {
+        match self {
+            FormatRequest::Fudisks(o) => {
+                let mut args = o.to_dbus_opts();
+                args.insert("no-block", Value::from(false));
+                (o.fs().as_str(), args)
+            }
+            FormatRequest::Raw {
+                fs,
+                label,
+                mkfs_args,
+                quick,
+                ..
+            } => {
+                let mut args: HashMap<&'static str, Value<'_>> = HashMap::new();
+                if let Some(lbl) = label {
+                    args.insert("label", Value::from(lbl.as_str()));
+                }
+                if !mkfs_args.is_empty() {
+                    args.insert("mkfs-args", Value::from(mkfs_args.clone()));
+                }
+                // Same meaning as fudisks gives `quick`.
+                if *quick {
+                    args.insert("no-verify", Value::from(true));
+                }
+                args.insert("no-block", Value::from(false));
+                (fs.name(), args)
+            }
+        }
+    }
+
+    /// The filesystem and `Block.Format` options, for plans.
+    fn describe(&self) -> String {
+        let (fs, args) = self.block_format_args();
+        let mut args: Vec<String> = args
+            .iter()
+            .filter(|(k, _)| **k != "no-block")
+            .map(|(k, v)| format!("{k}={}", show_value(v)))
+            .collect();
+        args.sort();
+        let mut out = fs.to_string();
+        if !args.is_empty() {
+            out.push_str(&format!(" ({})", args.join(", ")));
+        }
+        if let FormatRequest::Raw {
+            volume_id: Some(id),
+            ..
+        } = self
+        {
+            out.push_str(&format!(", then set the volume ID to {id}"));
+        }
+        out
+    }
+}
+
+fn show_value(v: &Value<'_>) -> String {
+    match v {
+        Value::Str(s) => format!("\"{s}\""),
+        Value::Bool(b) => b.to_string(),
+        Value::U32(n) => n.to_string(),
+        Value::U64(n) => n.to_string(),
+        Value::Array(a) => format!(
+            "[{}]",
+            a.iter().map(show_value).collect::<Vec<_>>().join(" ")
+        ),
+        other => other.to_string(),
+    }
+}
+
+#[derive(Default)]
+struct Identifiers {
+    uuid: Option<String>,
+    serial: Option<String>,
+    wwn: Option<String>,
+    links: Vec<String>,
+}
 
 pub(crate) struct UdisksBackend {
     ud: ud::Udisks,
+    // fudisks covers formatting; partition-level calls go through the raw client.
+    client: ud2::Client,
     ui_tx: crossbeam_channel::Sender<Msg>,
+    safety: SafetyConfig,
 }
 
 impl UdisksBackend {
-    pub(crate) async fn new(ui_tx: crossbeam_channel::Sender<Msg>) -> Result<Self> {
+    pub(crate) async fn new(
+        ui_tx: crossbeam_channel::Sender<Msg>,
+        safety: SafetyConfig,
+    ) -> Result<Self> {
         let ud = ud::Udisks::connect_system().await?;
         //  Quietly check we actually have a udisks2 service!
         ud.list_devices().await.map_err(anyhow::Error::from)?;
-        Ok(Self { ud, ui_tx })
+        let client = ud2::Client::new().await?;
+        Ok(Self {
+            ud,
+            client,
+            ui_tx,
+            safety,
+        })
+    }
+
+    fn object(&self, obj_path: &str) -> Result<ud2::Object> {
+        self.client.object(obj_path).map_err(anyhow::Error::from)
+    }
+
+    async fn wait_for_interface(&self, obj_path: &str, iface: &str) -> Result<()> {
+        let deadline = Instant::now() + SETTLE_TIMEOUT;
+        loop {
+            let objects = self.client.object_manager().get_managed_objects().await?;
+            let found = objects
+                .iter()
+                .any(|(path, ifaces)| path.as_str() == obj_path && ifaces.contains_key(iface));
+            if found {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                bail!("Timed out waiting for {iface} on {obj_path}");
+            }
+            sleep(SETTLE_POLL).await;
+        }
+    }
+
+    async fn force_unmount(&self, obj_path: &str) -> Result<()> {
+        let fs = match self.object(obj_path)?.filesystem().await {
+            Ok(fs) => fs,
+            Err(ud2::Error::Zbus(ud2::zbus::Error::InterfaceNotFound)) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut opts: HashMap<&str, Value<'_>> = HashMap::new();
+        opts.insert("force", Value::from(true));
+        match fs.unmount(opts).await {
+            Ok(()) | Err(ud2::Error::NotMounted) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn unmount_children(&self, disk_obj_path: &str) -> Result<()> {
+        let objects = self.client.object_manager().get_managed_objects().await?;
+        for (path, ifaces) in objects {
+            if !ifaces.contains_key(IFACE_PARTITION) {
+                continue;
+            }
+            let Ok(part) = self.object(path.as_str())?.partition().await else {
+                continue;
+            };
+            if part.table().await.ok().as_deref().map(|t| t.as_str()) != Some(disk_obj_path) {
+                continue;
+            }
+            self.force_unmount(path.as_str()).await?;
+        }
+        Ok(())
+    }
+
+    /// Wipes `disk_obj_path` and writes an empty partition table.
+    async fn create_table(&self, disk_obj_path: &str, table: PartitionTable) -> Result<()> {
+        let block = self.object(disk_obj_path)?.block().await?;
+        let table_str = match table {
+            PartitionTable::Gpt => "gpt",
+            PartitionTable::Dos => "dos",
+            PartitionTable::None => bail!("No partition table requested"),
+        };
+        let mut opts: HashMap<&str, Value<'_>> = HashMap::new();
+        opts.insert("no-block", Value::from(false));
+        block.format(table_str, opts).await?;
+        self.wait_for_interface(disk_obj_path, IFACE_PARTITION_TABLE)
+            .await
+    }
+
+    /// Creates a partition spanning `size` bytes at `offset` (0 for either
+    /// lets UDisks pick) and returns its object path. `name` must be empty
+    /// outside GPT.
+    async fn create_partition(
+        &self,
+        disk_obj_path: &str,
+        offset: u64,
+        size: u64,
+        type_id: &str,
+        name: &str,
+    ) -> Result<String> {
+        let pt = self.object(disk_obj_path)?.partition_table().await?;
+        let opts: HashMap<&str, Value<'_>> = HashMap::new();
+        let part_path = pt
+            .create_partition(offset, size, type_id, name, opts)
+            .await?
+            .to_string();
+        self.wait_for_interface(&part_path, IFACE_PARTITION).await?;
+        // UDisks may move a start it considers misaligned.
+        if offset != 0
+            && let Some(actual) = self.partition_offset(&part_path).await
+            && actual != offset
+        {
+            self.ui_tx
+                .emit(Msg::Progress(ProgressEvent::Message(format!(
+                    "Partition placed at {actual} bytes instead of {offset}"
+                ))));
+        }
+        Ok(part_path)
+    }
+
+    /// The alignment to use on `disk_obj_path`, in bytes.
+    async fn alignment_bytes(&self, disk_obj_path: &str, alignment: Alignment) -> Result<u64> {
+        if let Alignment::Bytes(bytes) = alignment {
+            return Ok(bytes);
+        }
+        let block = self.object(disk_obj_path)?.block().await?;
+        let dev = String::from_utf8_lossy(&block.device().await?)
+            .trim_end_matches('\0')
+            .to_string();
+        let name = dev.rsplit('/').next().unwrap_or_default();
+        // Some USB bridges report nonsense such as 33553920; only trust
+        // powers of two.
+        let optimal =
+            std::fs::read_to_string(format!("/sys/class/block/{name}/queue/optimal_io_size"))
+                .ok()
+                .and_then(|s| s.trim().parse::<u64>().ok())
+                .filter(|&n| n >= SECTOR && n.is_power_of_two());
+        Ok(optimal.unwrap_or(DEFAULT_ALIGNMENT))
+    }
+
+    async fn set_partition_flags(
+        &self,
+        part_obj_path: &str,
+        table: PartitionTable,
+        flags: PartitionFlags,
+    ) -> Result<()> {
+        use ud2::partition::PartitionFlags as Ud2Flags;
+
+        let part = self.object(part_obj_path)?.partition().await?;
+        let mut bits = part.flags().await?;
+        match table {
+            PartitionTable::Gpt => {
+                bits.set(Ud2Flags::LegacyBIOSBootable, flags.bootable);
+                bits.set(Ud2Flags::Hidden, flags.hidden);
+            }
+            // MBR hides partitions through the type byte instead.
+            PartitionTable::Dos => bits.set(Ud2Flags::Bootable, flags.bootable),
+            PartitionTable::None => return Ok(()),
+        }
+        if bits != part.flags().await? {
+            part.set_flags(bits, HashMap::new()).await?;
+        }
+        Ok(())
     }
 
     async fn forward_progress_until_complete(
         handle: ud::JobHandle,
         tx: crossbeam_channel::Sender<Msg>,
+        report_success: bool,
     ) -> Result<()> {
         let mut stream = handle.watch();
         while let Some(evt) = stream.next().await {
@@ -33,7 +305,9 @@ impl UdisksBackend {
                 }
                 ud::JobEvent::Completed(res) => match res {
                     Ok(()) => {
-                        tx.emit(Msg::Progress(ProgressEvent::Completed(Ok(()))));
+                        if report_success {
+                            tx.emit(Msg::Progress(ProgressEvent::Completed(Ok(()))));
+                        }
                         return Ok(());
                     }
                     Err(e) => {
@@ -46,62 +320,558 @@ impl UdisksBackend {
         bail!("UDisks job ended unexpectedly without completion");
     }
 
-    fn to_ud_opts(opts: &FormatOptions) -> Result<ud::FormatOptions> {
+    /// Formats an existing partition and forwards the job's progress. Only the
+    /// last job of an operation should report success, so front-ends don't
+    /// consider the whole operation done early.
+    async fn run_format_job(
+        &self,
+        part_obj_path: &str,
+        req: &FormatRequest,
+        last: bool,
+    ) -> Result<()> {
+        let FormatRequest::Fudisks(ud_opts) = req else {
+            self.force_unmount(part_obj_path).await?;
+            return self.run_block_format(part_obj_path, req, last).await;
+        };
+        let handle = self
+            .ud
+            .format_partition(part_obj_path, ud_opts)
+            .await
+            .map_err(anyhow::Error::from)?;
+        let job_id = handle.path().to_string();
+        self.ui_tx
+            .emit(Msg::Progress(ProgressEvent::JobStarted(job_id)));
+        Self::forward_progress_until_complete(handle, self.ui_tx.clone(), last).await
+    }
+
+    /// Calls `Block.Format` on `obj_path` and waits for it, reporting
+    /// completion the same way `forward_progress_until_complete` does.
+    async fn run_block_format(
+        &self,
+        obj_path: &str,
+        req: &FormatRequest,
+        last: bool,
+    ) -> Result<()> {
+        let block = self.object(obj_path)?.block().await?;
+        let (fs, args) = req.block_format_args();
+        let mut res = self.run_with_job(obj_path, block.format(fs, args)).await;
+        if res.is_ok()
+            && let FormatRequest::Raw {
+                volume_id: Some(id),
+                ..
+            } = req
+        {
+            res = self.set_volume_id(obj_path, id).await;
+        }
+        match &res {
+            Ok(()) if last => self
+                .ui_tx
+                .emit(Msg::Progress(ProgressEvent::Completed(Ok(())))),
+            Ok(()) => {}
+            Err(e) => self
+                .ui_tx
+                .emit(Msg::Progress(ProgressEvent::Completed(Err(e.to_string())))),
+        }
+        res
+    }
+
+    async fn partition_offset(&self, obj_path: &str) -> Option<u64> {
+        let part = self.object(obj_path).ok()?.partition().await.ok()?;
+        part.offset().await.ok()
+    }
+
+    /// The object path and device node of the disk holding partition
+    /// `obj_path`.
+    async fn partition_disk(&self, obj_path: &str) -> Option<(String, String)> {
+        let part = self.object(obj_path).ok()?.partition().await.ok()?;
+        let table = part.table().await.ok()?.as_str().to_string();
+        let block = self.object(&table).ok()?.block().await.ok()?;
+        let dev = String::from_utf8_lossy(&block.device().await.ok()?)
+            .trim_end_matches('\0')
+            .to_string();
+        Some((table, dev))
+    }
+
+    /// Stable names of a device, for finding it again; missing ones are left
+    /// empty.
+    async fn identifiers(&self, obj_path: &str) -> Identifiers {
+        let mut ids = Identifiers::default();
+        let Ok(block) = self.object(obj_path) else {
+            return ids;
+        };
+        let Ok(block) = block.block().await else {
+            return ids;
+        };
+        ids.uuid = block.id_uuid().await.ok().filter(|u| !u.is_empty());
+        ids.links = block
+            .symlinks()
+            .await
+            .unwrap_or_default()
+            .iter()
+            .map(|l| {
+                String::from_utf8_lossy(l)
+                    .trim_end_matches('\0')
+                    .to_string()
+            })
+            .filter(|l| l.starts_with("/dev/disk/by-"))
+            .collect();
+        if let Ok(drive) = self.client.drive_for_block(&block).await {
+            ids.serial = drive.serial().await.ok().filter(|s| !s.is_empty());
+            ids.wwn = drive.wwn().await.ok().filter(|w| !w.is_empty());
+        }
+        ids
+    }
+
+    /// The device's fingerprint as it is now, read the way
+    /// `list_block_devices` reads it.
+    async fn fingerprint(&self, obj_path: &str) -> Result<Fingerprint> {
+        let block = self.object(obj_path)?.block().await?;
+        let mut fp = Fingerprint {
+            size: block.size().await.ok(),
+            ..Fingerprint::default()
+        };
+        if let Ok(drive) = self.client.drive_for_block(&block).await {
+            fp.serial = drive.serial().await.ok().filter(|s| !s.is_empty());
+            fp.wwn = drive.wwn().await.ok().filter(|w| !w.is_empty());
+            let vendor = drive.vendor().await.unwrap_or_default();
+            let model = drive.model().await.unwrap_or_default();
+            let vm = format!("{} {}", vendor.trim(), model.trim())
+                .trim()
+                .to_string();
+            fp.model = Some(vm).filter(|vm| !vm.is_empty());
+        }
+        Ok(fp)
+    }
+
+    async fn connection_bus(&self, obj_path: &str) -> Option<String> {
+        let block = self.object(obj_path).ok()?.block().await.ok()?;
+        let drive = self.client.drive_for_block(&block).await.ok()?;
+        drive.connection_bus().await.ok().filter(|b| !b.is_empty())
+    }
+
+    /// Whether the device is in an SD/MMC slot or a card reader.
+    async fn takes_sd_cards(&self, obj_path: &str) -> bool {
+        use ud2::drive::MediaCompatibility as Media;
+        let media = async {
+            let block = self.object(obj_path).ok()?.block().await.ok()?;
+            let drive = self.client.drive_for_block(&block).await.ok()?;
+            drive.media_compatibility().await.ok()
+        };
+        media.await.unwrap_or_default().iter().any(|m| {
+            matches!(
+                m,
+                Media::FlashSd
+                    | Media::FlashSdhc
+                    | Media::FlashSdxc
+                    | Media::FlashSdio
+                    | Media::FlashSdCombo
+                    | Media::FlashMmc
+            )
+        })
+    }
+
+    /// The RAID array, LVM volume group or ZFS pool a member device belongs
+    /// to.
+    async fn member_of(&self, d: &ud::Device) -> Option<String> {
+        let label = d.label.clone().filter(|l| !l.is_empty());
+        match d.fs_type.as_deref()? {
+            "linux_raid_member" => {
+                // An assembled array has a name and level; otherwise the
+                // superblock's "host:name" label is all there is.
+                let block = self.object(&d.object_path).ok()?.block().await.ok()?;
+                let array = block.mdraid_member().await.ok()?;
+                if array.as_str() == "/" {
+                    return label;
+                }
+                let md = self.client.object(array).ok()?.mdraid().await.ok()?;
+                let name = md.name().await.ok()?;
+                match md.level().await {
+                    Ok(level) if !level.is_empty() => Some(format!("{name} ({level})")),
+                    _ => Some(name),
+                }
+            }
+            "LVM2_member" => {
+                let dev_path = d.dev_path.clone();
+                tokio::task::spawn_blocking(move || lvm_volume_group(&dev_path))
+                    .await
+                    .ok()
+                    .flatten()
+            }
+            _ => label,
+        }
+    }
+
+    /// Why the device at `obj_path` holds the running system, if it does.
+    async fn system_use(&self, obj_path: &str) -> Result<Option<String>> {
+        let devs = self.ud.list_devices().await.map_err(anyhow::Error::from)?;
+        let Some(dev) = devs.iter().find(|d| d.object_path == obj_path) else {
+            return Ok(None);
+        };
+        Ok(system::disk_of(&dev.dev_path).and_then(|disk| system_disks(&devs).remove(&disk)))
+    }
+
+    async fn mount_points(&self, obj_path: &str) -> Vec<String> {
+        let Ok(obj) = self.object(obj_path) else {
+            return Vec::new();
+        };
+        let Ok(fs) = obj.filesystem().await else {
+            return Vec::new();
+        };
+        fs.mount_points()
+            .await
+            .unwrap_or_default()
+            .iter()
+            .map(|mp| {
+                String::from_utf8_lossy(mp)
+                    .trim_end_matches('\0')
+                    .to_string()
+            })
+            .collect()
+    }
+
+    /// The GPT name of a partition; `None` when it has none or can't be read.
+    async fn partition_name(&self, obj_path: &str) -> Option<String> {
+        let part = self.object(obj_path).ok()?.partition().await.ok()?;
+        part.name().await.ok().filter(|n| !n.is_empty())
+    }
+
+    async fn set_volume_id(&self, obj_path: &str, id: &str) -> Result<()> {
+        self.ui_tx.emit(Msg::Progress(ProgressEvent::Message(
+            "Setting volume ID...".into(),
+        )));
+        self.wait_for_interface(obj_path, IFACE_FILESYSTEM).await?;
+        let fs = self.object(obj_path)?.filesystem().await?;
+        fs.set_uuid(id, HashMap::new()).await?;
+        Ok(())
+    }
+
+    /// Awaits a blocking UDisks call on `obj_path`, forwarding progress from
+    /// the job UDisks creates for it so it can be watched and cancelled.
+    async fn run_with_job<T>(
+        &self,
+        obj_path: &str,
+        call: impl Future<Output = ud2::Result<T>>,
+    ) -> Result<T> {
+        let obj = self.object(obj_path)?;
+        let mut job: Option<ud2::job::JobProxy<'_>> = None;
+        let mut ticker = interval(JOB_POLL);
+        tokio::pin!(call);
+        loop {
+            tokio::select! {
+                res = &mut call => return res.map_err(anyhow::Error::from),
+                _ = ticker.tick() => match &job {
+                    None => {
+                        if let Some(path) = self.client.jobs_for_object(&obj).await.into_iter().next() {
+                            self.ui_tx
+                                .emit(Msg::Progress(ProgressEvent::JobStarted(path.to_string())));
+                            job = self.object(path.as_str())?.job().await.ok();
+                        }
+                    }
+                    Some(j) => {
+                        if j.progress_valid().await.unwrap_or(false)
+                            && let Ok(p) = j.progress().await
+                        {
+                            self.ui_tx.emit(Msg::Progress(ProgressEvent::Percent(p * 100.0)));
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    /// Runs `Filesystem.Check` (or `Repair`) on an unmounted filesystem and
+    /// returns whether it reported success.
+    async fn fsck(&self, obj_path: &str, repair: bool) -> Result<bool> {
+        // Checking unmounts the filesystem first, which would pull it from
+        // under the running system.
+        if let Some(why) = self.system_use(obj_path).await? {
+            bail!("{obj_path} {why}; the running system depends on it");
+        }
+        self.force_unmount(obj_path).await?;
+        let fs = match self.object(obj_path)?.filesystem().await {
+            Ok(fs) => fs,
+            Err(ud2::Error::Zbus(ud2::zbus::Error::InterfaceNotFound)) => {
+                bail!("{obj_path} has no filesystem to check")
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if repair {
+            self.ui_tx.emit(Msg::Progress(ProgressEvent::Message(
+                "Repairing filesystem...".into(),
+            )));
+            self.run_with_job(obj_path, fs.repair(HashMap::new())).await
+        } else {
+            self.ui_tx.emit(Msg::Progress(ProgressEvent::Message(
+                "Checking filesystem...".into(),
+            )));
+            self.run_with_job(obj_path, fs.check(HashMap::new())).await
+        }
+    }
+
+    fn finish_fsck(&self, res: Result<FsckReport>) -> Result<FsckReport> {
+        match &res {
+            Ok(report) => {
+                self.ui_tx.emit(Msg::Progress(ProgressEvent::Message(
+                    report.summary().into(),
+                )));
+                self.ui_tx
+                    .emit(Msg::Progress(ProgressEvent::Completed(Ok(()))));
+            }
+            Err(e) => self
+                .ui_tx
+                .emit(Msg::Progress(ProgressEvent::Completed(Err(e.to_string())))),
+        }
+        res
+    }
+
+    /// Refuses targets the system, the safety policy or `opts` rule out, and
+    /// returns whether the target is a partition.
+    async fn check_target(&self, obj_path: &str, opts: &FormatOptions) -> Result<bool> {
+        if let Some(why) = self.system_use(obj_path).await? {
+            bail!("{obj_path} {why}; the running system depends on it");
+        }
+        let found = self.fingerprint(obj_path).await?;
+        let links = self.identifiers(obj_path).await.links;
+        if let Some(why) = self.safety.refusal(&found, &links) {
+            bail!("{obj_path} {why}");
+        }
+        if let Some(expected) = &opts.expected {
+            expected.verify(&found, obj_path)?;
+        }
+        let is_partition = self
+            .ud
+            .is_partition(obj_path)
+            .await
+            .map_err(anyhow::Error::from)?;
+        if is_partition {
+            if opts.boot_preset.is_some() {
+                bail!("The bootable (UEFI) preset needs a whole disk, not a partition");
+            }
+            if opts.partition_name.is_some() {
+                bail!("A partition name can only be set on partitions diskfmt creates");
+            }
+            if opts.sd_spec {
+                bail!("SD spec formatting needs the whole card, not a partition");
+            }
+        }
+        Ok(is_partition)
+    }
+
+    fn format_request(opts: &FormatOptions) -> FormatRequest {
         let label = opts.label.clone();
         let quick = opts.quick;
         let sz = opts.cluster_or_block_size;
-        match opts.fs.as_str() {
-            "ext4" => Ok(ud::FormatOptions::Ext4 {
+        let ud_opts = match opts.fs {
+            Filesystem::Ext4 => Some(ud::FormatOptions::Ext4 {
                 label,
                 block_size: sz,
                 quick,
             }),
-            "xfs" => Ok(ud::FormatOptions::Xfs {
+            Filesystem::Xfs => Some(ud::FormatOptions::Xfs {
                 label,
                 block_size: sz,
                 quick,
             }),
-            "btrfs" => Ok(ud::FormatOptions::Btrfs {
+            Filesystem::Btrfs => Some(ud::FormatOptions::Btrfs {
                 label,
                 nodesize: sz,
                 quick,
             }),
-            "exfat" => Ok(ud::FormatOptions::Exfat {
+            Filesystem::Exfat => Some(ud::FormatOptions::Exfat {
                 label,
                 cluster_size: sz,
                 quick,
             }),
-            "ntfs" => Ok(ud::FormatOptions::Ntfs {
+            Filesystem::Ntfs => Some(ud::FormatOptions::Ntfs {
                 label,
                 cluster_size: sz,
                 quick,
             }),
-            "vfat" => Ok(ud::FormatOptions::Vfat {
+            Filesystem::Vfat => Some(ud::FormatOptions::Vfat {
                 label,
                 sectors_per_cluster: sz,
                 quick,
             }),
-            other => bail!("Unsupported filesystem: {other}"),
+            Filesystem::Ext3
+            | Filesystem::Ext2
+            | Filesystem::F2fs
+            | Filesystem::Udf
+            | Filesystem::HfsPlus
+            | Filesystem::Swap => None,
+        };
+        match ud_opts {
+            // fudisks has no way to pass extra mkfs arguments.
+            Some(o) if opts.advanced.is_empty() => FormatRequest::Fudisks(o),
+            _ => Self::raw_request(opts, opts.fs.mkfs_args(sz, &opts.advanced)),
+        }
+    }
+
+    fn raw_request(opts: &FormatOptions, mkfs_args: Vec<String>) -> FormatRequest {
+        FormatRequest::Raw {
+            fs: opts.fs,
+            label: opts.label.clone(),
+            mkfs_args,
+            quick: opts.quick,
+            volume_id: opts
+                .advanced
+                .volume_id
+                .clone()
+                .filter(|_| opts.fs.info().mkfs_volume_id_arg.is_none()),
         }
     }
+
+    /// The request formatting `volume` on a partition at `offset` bytes
+    /// spanning `size`.
+    fn volume_request(volume: &Volume, offset: u64, size: u64) -> FormatRequest {
+        let opts = &volume.opts;
+        let Some(layout) = &volume.sd else {
+            return Self::format_request(opts);
+        };
+        let mut mkfs_args = layout.mkfs_args(offset, size);
+        mkfs_args.extend(opts.fs.mkfs_args(None, &opts.advanced));
+        Self::raw_request(opts, mkfs_args)
+    }
+
+    /// The geometry of `obj_path`, or `None` when it is a partition.
+    async fn geometry(
+        &self,
+        obj_path: &str,
+        is_partition: bool,
+        alignment: Alignment,
+    ) -> Result<Option<Geometry>> {
+        if is_partition {
+            return Ok(None);
+        }
+        let capacity = self.object(obj_path)?.block().await?.size().await?;
+        let align = self.alignment_bytes(obj_path, alignment).await?;
+        Ok(Some(Geometry { capacity, align }))
+    }
+
+    /// Runs `steps` on `obj_path` and returns the object path of the last
+    /// filesystem created.
+    async fn run_steps(
+        &self,
+        obj_path: &str,
+        steps: &[Step],
+        disk: Option<Geometry>,
+    ) -> Result<String> {
+        let last_format = steps.iter().rposition(|s| matches!(s, Step::Format { .. }));
+        let mut table = PartitionTable::None;
+        let mut parts: Vec<String> = Vec::new();
+        let mut formatted = obj_path.to_string();
+        for (i, step) in steps.iter().enumerate() {
+            if let Some(msg) = step.message() {
+                self.ui_tx.emit(Msg::Progress(ProgressEvent::Message(msg)));
+            }
+            match step {
+                Step::Unmount { partitions: false } => self.force_unmount(obj_path).await?,
+                Step::Unmount { partitions: true } => {
+                    let _ = self.force_unmount(obj_path).await;
+                    self.unmount_children(obj_path).await?;
+                }
+                Step::CreateTable(t) => {
+                    table = *t;
+                    self.create_table(obj_path, table).await?;
+                }
+                Step::CreatePartition {
+                    start,
+                    size,
+                    type_id,
+                    name,
+                    flags,
+                } => {
+                    let offset = match (start, parts.last()) {
+                        (Start::At(offset), _) => *offset,
+                        (Start::AfterPrevious, Some(prev)) => {
+                            let prev = self.object(prev)?.partition().await?;
+                            let align = disk.map_or(DEFAULT_ALIGNMENT, |d| d.align);
+                            (prev.offset().await? + prev.size().await?).next_multiple_of(align)
+                        }
+                        (Start::AfterPrevious, None) => 0,
+                    };
+                    let part_path = self
+                        .create_partition(obj_path, offset, *size, type_id, name)
+                        .await?;
+                    self.set_partition_flags(&part_path, table, *flags).await?;
+                    parts.push(part_path);
+                }
+                Step::Format { target, volume, .. } => {
+                    let last = last_format == Some(i);
+                    match target {
+                        // A whole disk without a partition table.
+                        Target::Device if disk.is_some() => {
+                            let req = Self::volume_request(volume, 0, 0);
+                            self.run_block_format(obj_path, &req, last).await?;
+                            formatted = obj_path.to_string();
+                        }
+                        Target::Device => {
+                            let req = Self::volume_request(volume, 0, 0);
+                            self.run_format_job(obj_path, &req, last).await?;
+                            formatted = obj_path.to_string();
+                        }
+                        Target::Partition(n) => {
+                            let Some(path) = parts.get(*n) else {
+                                bail!("Partition {n} was never created");
+                            };
+                            // Lay out to where UDisks actually put the partition.
+                            let part = self.object(path)?.partition().await?;
+                            let req = Self::volume_request(
+                                volume,
+                                part.offset().await?,
+                                part.size().await?,
+                            );
+                            self.run_format_job(path, &req, last).await?;
+                            formatted = path.clone();
+                        }
+                    }
+                }
+            }
+        }
+        Ok(formatted)
+    }
 }
 
 #[async_trait]
 impl Backend for UdisksBackend {
-    async fn list_block_devices(&self) -> Result<Vec<BlockDevice>> {
+    async fn list_block_devices(&self, include_fixed: bool) -> Result<Vec<BlockDevice>> {
         let devs = self.ud.list_devices().await.map_err(anyhow::Error::from)?;
-        let out = devs
-            .into_iter()
-            .filter(|d| {
-                if d.is_optical || d.dev_path.starts_with("/dev/sr") {
-                    return false;
-                }
-                if !d.is_removable {
-                    return false;
-                }
-                true
-            })
-            .map(|d| BlockDevice {
+        let system_disks = system_disks(&devs);
+        let mut out = Vec::new();
+        for d in devs {
+            if d.is_optical || d.dev_path.starts_with("/dev/sr") {
+                continue;
+            }
+            let bus = self.connection_bus(&d.object_path).await;
+            let system_use =
+                system::disk_of(&d.dev_path).and_then(|disk| system_disks.get(&disk).cloned());
+            if !d.is_removable
+                && (!include_fixed
+                    || system_use.is_some()
+                    || !bus.as_deref().is_some_and(|b| EXTERNAL_BUSES.contains(&b)))
+            {
+                continue;
+            }
+            let (partition_name, partition_offset, disk) = if d.is_partition {
+                (
+                    self.partition_name(&d.object_path).await,
+                    self.partition_offset(&d.object_path).await,
+                    self.partition_disk(&d.object_path).await,
+                )
+            } else {
+                (None, None, None)
+            };
+            let (table_object, disk_path) = disk.unzip();
+            let ids = self.identifiers(&d.object_path).await;
+            let sd_card = d.dev_path.starts_with("/dev/mmcblk")
+                || bus.as_deref() == Some("sdio")
+                || self.takes_sd_cards(&d.object_path).await;
+            let member_of = self.member_of(&d).await;
+            let mount_points = if d.is_mounted {
+                self.mount_points(&d.object_path).await
+            } else {
+                Vec::new()
+            };
+            let dev = BlockDevice {
                 dev_path: d.dev_path,
                 object_path: d.object_path,
                 fs_type: d.fs_type,
@@ -109,53 +879,92 @@ impl Backend for UdisksBackend {
                 size_bytes: d.size_bytes,
                 vendor_model: d.vendor_model,
                 is_partition: d.is_partition,
-            })
-            .collect();
+                removable: d.is_removable,
+                bus,
+                sd_card,
+                partition_name,
+                partition_offset,
+                table_object,
+                disk_path,
+                mount_points,
+                uuid: ids.uuid,
+                serial: ids.serial,
+                wwn: ids.wwn,
+                links: ids.links,
+                member_of,
+                system_use,
+            };
+            if self
+                .safety
+                .refusal(&dev.fingerprint(), &dev.links)
+                .is_some()
+            {
+                continue;
+            }
+            out.push(dev);
+        }
         Ok(out)
     }
 
     async fn format(&self, obj_path: &str, opts: super::FormatOptions) -> Result<String> {
-        let ud_opts = Self::to_ud_opts(&opts)?;
-        if self
-            .ud
-            .is_partition(obj_path)
-            .await
-            .map_err(anyhow::Error::from)?
-        {
-            let handle = self
-                .ud
-                .format_partition(obj_path, &ud_opts)
-                .await
-                .map_err(anyhow::Error::from)?;
-            let job_id = handle.path().to_string();
-            let _ = self
-                .ui_tx
-                .emit(Msg::Progress(ProgressEvent::JobStarted(job_id.clone())));
-            Self::forward_progress_until_complete(handle, self.ui_tx.clone()).await?;
-            Ok(obj_path.to_string())
-        } else {
-            let table = match opts.partition_table {
-                Some(crate::backends::PartitionTable::Dos) => ud::PartitionTable::Dos,
-                _ => ud::PartitionTable::Gpt,
-            };
-            let _ = self.ui_tx.emit(Msg::Progress(ProgressEvent::Message(
-                "Creating partition table...".into(),
-            )));
-            let (new_part_path, handle) = self
-                .ud
-                .format_block_device_with_table(obj_path, table, &ud_opts, true)
-                .await
-                .map_err(anyhow::Error::from)?;
-            let _ = self.ui_tx.emit(Msg::Progress(ProgressEvent::Message(
-                "Formatting partition...".into(),
-            )));
-            let job_id = handle.path().to_string();
-            let _ = self
-                .ui_tx
-                .emit(Msg::Progress(ProgressEvent::JobStarted(job_id.clone())));
-            Self::forward_progress_until_complete(handle, self.ui_tx.clone()).await?;
-            Ok(new_part_path)
+        let is_partition = self.check_target(obj_path, &opts).await?;
+        let disk = self
+            .geometry(obj_path, is_partition, opts.alignment)
+            .await?;
+        let steps = format_steps(&opts, disk)?;
+        self.run_steps(obj_path, &steps, disk).await
+    }
+
+    async fn plan_format(&self, obj_path: &str, opts: &FormatOptions) -> Result<Vec<String>> {
+        let is_partition = self.check_target(obj_path, opts).await?;
+        let disk = self
+            .geometry(obj_path, is_partition, opts.alignment)
+            .await?;
+        let steps = format_steps(opts, disk)?;
+        let block = self.object(obj_path)?.block().await?;
+        let dev = String::from_utf8_lossy(&block.device().await?)
+            .trim_end_matches('\0')
+            .to_string();
+        Ok(plan_steps(&steps, &dev, disk, |volume, offset, size| {
+            Self::volume_request(volume, offset, size).describe()
+        }))
+    }
+
+    async fn set_label(&self, obj_path: &str, label: &str) -> Result<()> {
+        let fs = self.object(obj_path)?.filesystem().await?;
+        fs.set_label(label, HashMap::new()).await?;
+        Ok(())
+    }
+
+    async fn check(&self, obj_path: &str) -> Result<FsckReport> {
+        let res = self.fsck(obj_path, false).await.map(|ok| FsckReport {
+            had_errors: !ok,
+            repair_attempted: false,
+            consistent: ok,
+        });
+        self.finish_fsck(res)
+    }
+
+    async fn repair(&self, obj_path: &str) -> Result<FsckReport> {
+        // Check first so the summary can tell what the repair changed.
+        let res = async {
+            if self.fsck(obj_path, false).await? {
+                return Ok(FsckReport {
+                    had_errors: false,
+                    repair_attempted: false,
+                    consistent: true,
+                });
+            }
+            self.fsck(obj_path, true).await?;
+            let consistent = self.fsck(obj_path, false).await?;
+            Ok(FsckReport {
+                had_errors: true,
+                repair_attempted: true,
+                consistent,
+            })
         }
+        .await;
+        self.finish_fsck(res)
     }
 
     async fn cancel(&self, job_id: &str) -> Result<()> {
@@ -165,3 +974,32 @@ impl Backend for UdisksBackend {
             .map_err(anyhow::Error::from)
     }
 }
+
+/// [`system::system_disks`] plus the disks of members of imported ZFS pools,
+/// which only UDisks' probing can tell apart.
+fn system_disks(devs: &[ud::Device]) -> HashMap<String, String> {
+    let mut disks = system::system_disks();
+    for d in devs {
+        if d.fs_type.as_deref() == Some("zfs_member")
+            && let Some(pool) = d.label.as_deref().filter(|p| system::zfs_pool_imported(p))
+            && let Some(disk) = system::disk_of(&d.dev_path)
+        {
+            disks
+                .entry(disk)
+                .or_insert_with(|| format!("is in the imported ZFS pool {pool}"));
+        }
+    }
+    disks
+}
+
+/// The volume group of the LVM physical volume `dev_path`, as `pvs` reports
+/// it; `None` without LVM tools or permission, or for an orphan volume.
+fn lvm_volume_group(dev_path: &str) -> Option<String> {
+    let out = Command::new("pvs")
+        .args(["--noheadings", "-o", "vg_name", dev_path])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let vg = String::from_utf8_lossy(&out.stdout).trim().to_string();
+    (out.status.success() && !vg.is_empty()).then_some(vg)
+}
@@ -0,0 +1,172 @@
+//! `list --json` and `list --output`: device fields as lsblk-style columns,
+//! for scripts.
+
+use crate::backends::{BlockDevice, human_size};
+use crate::utils;
+use clap::ValueEnum;
+use serde_json::{Map, Value, json};
+
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+pub enum Column {
+    /// Device node
+    Path,
+    /// Kernel device name
+    Name,
+    /// "disk" or "part"
+    Type,
+    /// Size (bytes in JSON)
+    Size,
+    /// Drive vendor and model
+    Model,
+    /// Drive serial number
+    Serial,
+    /// Drive World Wide Name
+    Wwn,
+    /// Connection bus (usb, sdio, ...)
+    Tran,
+    /// Removable media
+    Rm,
+    /// SD/MMC slot or card reader
+    Sd,
+    /// Filesystem or signature type
+    Fstype,
+    /// Filesystem label
+    Label,
+    /// Filesystem UUID
+    Uuid,
+    /// Partition name
+    Partlabel,
+    /// Partition start in bytes
+    Start,
+    /// Partition not on a 4 KiB boundary
+    Misaligned,
+    /// Mount points
+    Mountpoints,
+    /// RAID array, volume group or pool the device belongs to
+    Member,
+    /// Why the running system depends on the device's disk
+    System,
+    /// Every form `--path` accepts for the device
+    Ids,
+    /// /dev/disk/by-* links
+    Links,
+    /// Disk a partition is on
+    Parent,
+    /// Partitions of a disk
+    Children,
+    /// Backend object path
+    Objpath,
+}
+
+impl Column {
+    fn key(self) -> String {
+        self.to_possible_value()
+            .map(|v| v.get_name().to_string())
+            .unwrap_or_default()
+    }
+
+    fn value(self, dev: &BlockDevice, devs: &[BlockDevice]) -> Value {
+        match self {
+            Column::Path => json!(dev.dev_path),
+            Column::Name => json!(dev.dev_path.strip_prefix("/dev/").unwrap_or(&dev.dev_path)),
+            Column::Type => json!(if dev.is_partition { "part" } else { "disk" }),
+            Column::Size => json!(dev.size_bytes),
+            Column::Model => json!(dev.vendor_model),
+            Column::Serial => json!(dev.serial),
+            Column::Wwn => json!(dev.wwn),
+            Column::Tran => json!(dev.bus),
+            Column::Rm => json!(dev.removable),
+            Column::Sd => json!(dev.sd_card),
+            Column::Fstype => json!(dev.fs_type.as_deref().filter(|t| !t.is_empty())),
+            Column::Label => json!(dev.label.as_deref().filter(|l| !l.is_empty())),
+            Column::Uuid => json!(dev.uuid),
+            Column::Partlabel => json!(dev.partition_name),
+            Column::Start => json!(dev.partition_offset),
+            Column::Misaligned => json!(dev.is_misaligned()),
+            Column::Mountpoints => json!(dev.mount_points),
+            Column::Member => json!(dev.member_of),
+            Column::System => json!(dev.system_use),
+            Column::Ids => json!(utils::device_identifiers(dev)),
+            Column::Links => json!(dev.links),
+            Column::Parent => json!(dev.disk_path),
+            Column::Children => json!(
+                utils::partitions_of(devs, dev)
+                    .iter()
+                    .map(|p| &p.dev_path)
+                    .collect::<Vec<_>>()
+            ),
+            Column::Objpath => json!(dev.object_path),
+        }
+    }
+
+    /// The table cell for `value`: sizes human-readable, flags as 1/0 and
+    /// lists comma-separated, as lsblk prints them.
+    fn cell(self, value: &Value) -> String {
+        match value {
+            Value::Null => String::new(),
+            Value::Bool(b) => if *b { "1" } else { "0" }.to_string(),
+            Value::Number(n) if self == Column::Size => {
+                n.as_u64().map(human_size).unwrap_or_else(|| n.to_string())
+            }
+            Value::String(s) => s.clone(),
+            Value::Array(items) => items
+                .iter()
+                .map(|v| self.cell(v))
+                .collect::<Vec<_>>()
+                .join(","),
+            other => other.to_string(),
+        }
+    }
+}
+
+/// Prints `{"blockdevices": [...]}` with `columns` of each device, or every
+/// column when none are given.
+pub(crate) fn print_json(devs: &[BlockDevice], columns: &[Column]) {
+    let columns = if columns.is_empty() {
+        Column::value_variants()
+    } else {
+        columns
+    };
+    let rows: Vec<Value> = devs
+        .iter()
+        .map(|dev| {
+            let row: Map<String, Value> = columns
+                .iter()
+                .map(|c| (c.key(), c.value(dev, devs)))
+                .collect();
+            Value::Object(row)
+        })
+        .collect();
+    match serde_json::to_string_pretty(&json!({ "blockdevices": rows })) {
+        Ok(s) => println!("{s}"),
+        Err(err) => eprintln!("JSON error: {err}"),
+    }
+}
+
+/// Prints a table of `columns` under upper-case headings.
+pub(crate) fn print_table(devs: &[BlockDevice], columns: &[Column]) {
+    let mut rows = vec![
+        columns
+            .iter()
+            .map(|c| c.key().to_uppercase())
+            .collect::<Vec<_>>(),
+    ];
+    rows.extend(devs.iter().map(|dev| {
+        columns
+            .iter()
+            .map(|c| c.cell(&c.value(dev, devs)))
+            .collect::<Vec<_>>()
+    }));
+    let widths: Vec<usize> = (0..columns.len())
+        .map(|i| rows.iter().map(|r| r[i].chars().count()).max().unwrap_or(0))
+        .collect();
+    for row in rows {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, w)| format!("{cell:<w$}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        println!("{}", line.trim_end());
+    }
+}
@@ -1,29 +1,113 @@
-use crate::backends::PartitionTable;
-use crate::backends::ProgressEvent;
-use crate::common::{ConsoleReporter, Msg, ProgressReporter, make_backend};
+use crate::backends::filesystem::{BTRFS_CHECKSUMS, BTRFS_METADATA_PROFILES};
+use crate::backends::{AdvancedOptions, BootPreset, DEFAULT_ESP_SIZE, Filesystem, PartitionTable};
+use crate::backends::{Backend, BlockDevice, ProgressEvent};
+use crate::common::{ConsoleReporter, JsonReporter, Msg, ProgressReporter, make_backend};
+use crate::compat::{self, Target};
 #[cfg(feature = "gui")]
 use crate::style::{SchemeOpt, ThemeOpt};
 use crate::utils;
 use clap::ValueEnum;
+use clap::builder::{BoolishValueParser, PossibleValuesParser};
 #[allow(unused_imports)]
-use clap::{CommandFactory, Parser, Subcommand};
-use std::{process, time::Duration};
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use std::io::{self, IsTerminal, Write};
+use std::{process, sync::Arc, time::Duration};
+
+mod list;
 
 #[derive(Copy, Clone, Debug, ValueEnum)]
 #[value(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum PartitionTableOpt {
     Gpt,
     Dos,
+    /// No partition table: put the filesystem on the whole device (superfloppy)
+    None,
+}
+
+/// How `format` reports progress.
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+pub enum EventFormat {
+    /// Human-readable lines on stderr
+    Text,
+    /// One JSON object per line (NDJSON) on stdout
+    Json,
 }
 
 pub(crate) fn map_partition_table_opt(table: Option<PartitionTableOpt>) -> Option<PartitionTable> {
     match table {
         Some(PartitionTableOpt::Dos) => Some(PartitionTable::Dos),
         Some(PartitionTableOpt::Gpt) => Some(PartitionTable::Gpt),
+        Some(PartitionTableOpt::None) => Some(PartitionTable::None),
         None => None,
     }
 }
 
+/// Filesystem-specific options. Each one only applies to the filesystem(s) its
+/// name starts with.
+#[derive(Args, Debug)]
+#[command(next_help_heading = "Advanced")]
+pub struct AdvancedArgs {
+    /// ext2/3/4: percentage of blocks reserved for root
+    #[arg(long, value_name = "PERCENT")]
+    ext_reserved: Option<f64>,
+    /// ext2/3/4: bytes per inode
+    #[arg(long, value_name = "BYTES")]
+    ext_inode_ratio: Option<u64>,
+    /// ext4: create a journal
+    #[arg(long, value_name = "ON|OFF", value_parser = BoolishValueParser::new())]
+    ext_journal: Option<bool>,
+    /// ext2/3/4: comma-separated mke2fs feature flags, "^name" disables one
+    #[arg(long, value_name = "FLAGS", value_delimiter = ',')]
+    ext_features: Vec<String>,
+    /// vfat: FAT type (12, 16 or 32)
+    #[arg(long, value_name = "BITS")]
+    fat_size: Option<u8>,
+    /// btrfs: metadata profile
+    #[arg(long, value_name = "PROFILE", value_parser = PossibleValuesParser::new(BTRFS_METADATA_PROFILES))]
+    btrfs_metadata: Option<String>,
+    /// btrfs: checksum algorithm
+    #[arg(long, value_name = "ALGO", value_parser = PossibleValuesParser::new(BTRFS_CHECKSUMS))]
+    btrfs_csum: Option<String>,
+    /// ntfs: enable compression
+    #[arg(long, default_value_t = false)]
+    ntfs_compression: bool,
+    /// xfs: reflink support
+    #[arg(long, value_name = "ON|OFF", value_parser = BoolishValueParser::new())]
+    xfs_reflink: Option<bool>,
+    /// xfs: metadata checksums
+    #[arg(long, value_name = "ON|OFF", value_parser = BoolishValueParser::new())]
+    xfs_crc: Option<bool>,
+    /// Filesystem UUID, or volume serial for vfat/exfat (1234-ABCD) and ntfs/udf (16 hex digits)
+    #[arg(long, value_name = "ID")]
+    uuid: Option<String>,
+    /// Derive the UUID or volume serial from SEED, the same one every time
+    #[arg(long, value_name = "SEED", conflicts_with = "uuid")]
+    uuid_seed: Option<String>,
+}
+
+impl AdvancedArgs {
+    fn into_options(self, fs: Filesystem) -> Result<AdvancedOptions, String> {
+        let volume_id = match (self.uuid, self.uuid_seed) {
+            (Some(id), _) => Some(fs.parse_volume_id(&id)?),
+            (None, Some(seed)) => Some(fs.volume_id_from_seed(&seed)?),
+            (None, None) => None,
+        };
+        Ok(AdvancedOptions {
+            reserved_percent: self.ext_reserved,
+            inode_ratio: self.ext_inode_ratio,
+            journal: self.ext_journal,
+            ext_features: self.ext_features,
+            fat_size: self.fat_size,
+            volume_id,
+            btrfs_metadata: self.btrfs_metadata,
+            btrfs_checksum: self.btrfs_csum,
+            ntfs_compression: self.ntfs_compression,
+            xfs_reflink: self.xfs_reflink,
+            xfs_crc: self.xfs_crc,
+        })
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 pub struct Cli {
@@ -36,6 +120,16 @@ pub struct Cli {
     #[arg(long, global = true)]
     pub mock_backend: bool,
 
+    /// Also offer fixed drives on external buses (USB hard drives, NVMe
+    /// enclosures); disks holding the running system stay hidden
+    #[arg(long, global = true)]
+    pub all: bool,
+
+    /// Check everything and print what would be done, without changing any
+    /// device
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+
     #[cfg(feature = "gui")]
     /// UI color theme
     #[arg(long, value_enum, global = true)]
@@ -51,9 +145,24 @@ pub struct Cli {
 }
 
 #[derive(Subcommand, Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum Command {
     /// List available block devices
-    List,
+    List {
+        /// Print the devices as JSON (every column, or those given with --output)
+        #[arg(long, default_value_t = false)]
+        json: bool,
+        /// Comma-separated columns to print, as with lsblk (e.g. path,size,fstype,label)
+        #[arg(
+            short = 'o',
+            long,
+            value_enum,
+            value_delimiter = ',',
+            ignore_case = true,
+            value_name = "COLUMNS"
+        )]
+        output: Vec<list::Column>,
+    },
     /// Show or manage configuration
     Config {
         /// Print the effective theme and scheme after merging config and CLI
@@ -74,11 +183,11 @@ pub enum Command {
     },
     /// Format a device/partition with options similar to the GUI
     Format {
-        /// Object path or device identifier
+        /// Device node, /dev/disk/by-* link, LABEL=, UUID=, serial: or object path
         #[arg(long)]
         path: String,
-        /// Filesystem type (e.g., vfat, exfat, ntfs, ext4, xfs, btrfs)
-        #[arg(long)]
+        /// Filesystem type
+        #[arg(long, value_parser = PossibleValuesParser::new(Filesystem::names()))]
         fs: Option<String>,
         /// Volume label
         #[arg(long)]
@@ -86,12 +195,105 @@ pub enum Command {
         /// Use quick format
         #[arg(long, default_value_t = false)]
         quick: bool,
-        /// Allocation unit size choice (e.g., "Auto", "4096 bytes", "8 sectors")
+        /// Allocation unit size choice (e.g., "Auto", "4096 bytes", "8 sectors").
+        /// Omitted, the unit recommended for the device's capacity is used;
+        /// "Auto" leaves it to mkfs
         #[arg(long, value_name = "SIZE")]
         size: Option<String>,
         /// Partition table type for whole-disk format
         #[arg(long, value_enum)]
         table: Option<PartitionTableOpt>,
+        /// Partition type for whole-disk format: auto, esp, basic-data, linux,
+        /// a GPT type GUID or an MBR type byte (e.g. 0x0c)
+        #[arg(long, value_name = "TYPE", default_value = "auto")]
+        part_type: String,
+        /// GPT partition name for whole-disk format (defaults to the label)
+        #[arg(long, value_name = "NAME")]
+        part_name: Option<String>,
+        /// Mark the created partition bootable (legacy BIOS bootable on GPT)
+        #[arg(long, default_value_t = false)]
+        bootable: bool,
+        /// Mark the created partition hidden
+        #[arg(long, default_value_t = false)]
+        hidden: bool,
+        /// Bootable (UEFI) preset: a FAT32 ESP on GPT, or an active FAT partition on MBR
+        #[arg(long, default_value_t = false)]
+        uefi_boot: bool,
+        /// With --uefi-boot, add a data partition using --fs/--label after the boot partition
+        #[arg(long, default_value_t = false, requires = "uefi_boot")]
+        data_partition: bool,
+        /// Boot partition size in MiB when a data partition follows it (at
+        /// least 260, so it can hold FAT32)
+        #[arg(
+            long,
+            value_name = "MIB",
+            default_value_t = DEFAULT_ESP_SIZE / 1024 / 1024,
+            requires = "data_partition"
+        )]
+        esp_size: u64,
+        /// Partition alignment for whole-disk format: a size such as 1M
+        /// (default) or 4096, or "optimal" for the device's optimal I/O size
+        #[arg(long, value_name = "ALIGN")]
+        align: Option<String>,
+        /// Start of the first partition for whole-disk format, e.g. 4M
+        /// (defaults to one alignment unit)
+        #[arg(long, value_name = "SIZE")]
+        offset: Option<String>,
+        /// What the drive will be used with, comma-separated. Picks the
+        /// filesystem, partition table and allocation unit unless given, and
+        /// warns about incompatibilities
+        #[arg(
+            long = "target",
+            value_name = "TARGETS",
+            value_delimiter = ',',
+            value_parser = PossibleValuesParser::new(Target::names())
+        )]
+        targets: Vec<String>,
+        /// Don't ask for confirmation (required when stdin isn't a terminal)
+        #[arg(short = 'y', long, default_value_t = false)]
+        yes: bool,
+        /// Format even if the pre-flight checks warn about the options
+        #[arg(long, default_value_t = false)]
+        force: bool,
+        /// Lay out a whole SD card per the SD Association's specification:
+        /// MBR, FAT12/16, FAT32 or exFAT by capacity, aligned to erase blocks
+        #[arg(
+            long,
+            default_value_t = false,
+            conflicts_with_all = [
+                "fs", "size", "table", "part_type", "part_name", "bootable", "hidden", "uefi_boot",
+                "fat_size", "align", "offset", "targets"
+            ]
+        )]
+        sd_spec: bool,
+        /// Progress output: text lines on stderr, or json for one JSON object
+        /// per event on stdout (needs --yes unless --dry-run)
+        #[arg(long, value_enum, value_name = "FORMAT", default_value = "text")]
+        events: EventFormat,
+        #[command(flatten)]
+        advanced: AdvancedArgs,
+    },
+    /// Change the volume label of an existing filesystem without formatting
+    Label {
+        /// Device node, /dev/disk/by-* link, LABEL=, UUID=, serial: or object path
+        #[arg(long)]
+        path: String,
+        /// New volume label (empty to clear it)
+        label: String,
+    },
+    /// Check an existing filesystem for errors without changing it (reports
+    /// pass/fail only)
+    Check {
+        /// Device node, /dev/disk/by-* link, LABEL=, UUID=, serial: or object path
+        #[arg(long)]
+        path: String,
+    },
+    /// Try to repair an existing filesystem (checks it before and after;
+    /// reports whether errors were found, repaired or remain)
+    Repair {
+        /// Device node, /dev/disk/by-* link, LABEL=, UUID=, serial: or object path
+        #[arg(long)]
+        path: String,
     },
     /// Cancel a running format by job id
     Cancel {
@@ -100,6 +302,205 @@ pub enum Command {
     },
 }
 
+/// Shows what is about to be erased and exits unless the user types the
+/// device name or label back.
+fn confirm_format(dev: &BlockDevice, devs: &[BlockDevice]) {
+    if !io::stdin().is_terminal() {
+        eprintln!("Not formatting without confirmation: stdin is not a terminal; pass --yes");
+        process::exit(2);
+    }
+    println!("About to format:\n{}", utils::device_details(dev, devs));
+    let prompt = match dev.label.as_deref().filter(|l| !l.is_empty()) {
+        Some(lbl) => format!("the device name or its label \"{lbl}\""),
+        None => "the device name".to_string(),
+    };
+    print!("All data on it will be lost. Type {prompt} to continue: ");
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        process::exit(1);
+    }
+    if !utils::confirms_device(&answer, dev) {
+        eprintln!("Aborted");
+        process::exit(1);
+    }
+    if !dev.removable {
+        print!(
+            "{} is a {}, not removable media. Format it anyway? [y/N] ",
+            dev.dev_path,
+            utils::fixed_drive(dev)
+        );
+        let _ = io::stdout().flush();
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer).is_err() {
+            process::exit(1);
+        }
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            eprintln!("Aborted");
+            process::exit(1);
+        }
+    }
+}
+
+/// Prints the steps an operation on `dev` would take.
+fn print_plan(dev: &str, steps: &[String]) {
+    println!("Plan for {dev} (dry run, nothing was changed):");
+    for (i, step) in steps.iter().enumerate() {
+        println!("  {}. {step}", i + 1);
+    }
+}
+
+/// Prints an informational line on stdout, or on stderr when stdout carries
+/// JSON events.
+fn note(json: bool, msg: &str) {
+    if json {
+        eprintln!("{msg}");
+    } else {
+        println!("{msg}");
+    }
+}
+
+/// Prints progress messages until the operation reports completion or its task
+/// finishes.
+fn report_progress<T>(
+    rx: &crossbeam_channel::Receiver<Msg>,
+    task: &tokio::task::JoinHandle<T>,
+    reporter: &mut dyn ProgressReporter,
+) {
+    let mut done = false;
+
+    while !done {
+        match rx.recv_timeout(Duration::from_millis(50)) {
+            Ok(msg) => match msg {
+                Msg::Status(s) => reporter.status(&s),
+                Msg::Progress(ev) => {
+                    reporter.progress(&ev);
+                    if let ProgressEvent::Completed(_) = ev {
+                        done = true;
+                    }
+                }
+                #[cfg(feature = "gui")]
+                _ => {}
+            },
+            Err(crossbeam_channel::RecvTimeoutError::Timeout) => {}
+            Err(crossbeam_channel::RecvTimeoutError::Disconnected) => break,
+        }
+
+        if done || task.is_finished() {
+            break;
+        }
+    }
+}
+
+/// Prints `msg` on stderr and exits with `code`. With JSON events, a failed
+/// `result` carrying the same code ends the stream first.
+fn fail(stream: Option<&JsonReporter>, code: i32, msg: &str) -> ! {
+    eprintln!("{msg}");
+    if let Some(stream) = stream {
+        stream.result(Err((code, msg)));
+    }
+    process::exit(code);
+}
+
+async fn list_devices(
+    backend: &Arc<dyn Backend>,
+    all: bool,
+    stream: Option<&JsonReporter>,
+) -> Vec<BlockDevice> {
+    match backend.list_block_devices(all).await {
+        Ok(devs) => devs,
+        Err(e) => fail(stream, 1, &format!("List error: {e}")),
+    }
+}
+
+/// Resolves `path` among the listed devices, exiting if it can't. `all`
+/// says whether fixed drives were listed.
+fn require_device(
+    devs: &[BlockDevice],
+    path: &str,
+    all: bool,
+    stream: Option<&JsonReporter>,
+) -> BlockDevice {
+    match utils::resolve_device(devs, path) {
+        Ok(dev) => dev.clone(),
+        Err(err) => {
+            let mut msg = format!("Invalid target: {err}");
+            if !all {
+                msg.push_str(
+                    "\nFixed drives such as USB hard drives are only listed with --all; try again with it",
+                );
+            }
+            fail(stream, 1, &msg)
+        }
+    }
+}
+
+async fn run_fsck(
+    backend: &Arc<dyn Backend>,
+    rx: &crossbeam_channel::Receiver<Msg>,
+    path: &str,
+    repair: bool,
+    all: bool,
+    dry_run: bool,
+) {
+    let dev = require_device(&list_devices(backend, all, None).await, path, all, None);
+    if let Some(why) = &dev.system_use {
+        let op = if repair { "repair" } else { "check" };
+        fail(
+            None,
+            2,
+            &format!(
+                "Refusing to {op} {}: it has to be unmounted, but its disk {why}, and the running system depends on it",
+                dev.dev_path
+            ),
+        );
+    }
+    if dry_run {
+        let fs = dev.fs_type.as_deref().unwrap_or("unknown");
+        let mut steps = vec![
+            format!("Unmount {} if mounted", dev.dev_path),
+            format!("Check the {fs} filesystem"),
+        ];
+        if repair {
+            steps.push("If it has errors, repair it and check it again".to_string());
+        }
+        print_plan(&dev.dev_path, &steps);
+        return;
+    }
+    let be = backend.clone();
+    let obj_path = dev.object_path.clone();
+    let task = tokio::spawn(async move {
+        if repair {
+            be.repair(&obj_path).await
+        } else {
+            be.check(&obj_path).await
+        }
+    });
+
+    report_progress(rx, &task, &mut ConsoleReporter);
+
+    match task.await {
+        Ok(Ok(report)) => {
+            println!("{}: {}", dev.dev_path, report.summary());
+            if let Some(n) = report.note() {
+                eprintln!("Note: {n}");
+            }
+            if !report.consistent {
+                process::exit(1);
+            }
+        }
+        Ok(Err(e)) => {
+            let op = if repair { "Repair" } else { "Check" };
+            eprintln!("{op} failed: {e}");
+            process::exit(1);
+        }
+        Err(join_err) => {
+            eprintln!("Task failed to join: {join_err}");
+            process::exit(1);
+        }
+    }
+}
+
 impl Cli {
     pub async fn start(mut cli: Cli) -> anyhow::Result<()> {
         let command = match cli.command.take() {
@@ -125,10 +526,13 @@ impl Cli {
 
         match command {
             Command::Config { .. } => unreachable!("handled above"),
-            Command::List => match backend.list_block_devices().await {
+            Command::List { json, output } => match backend.list_block_devices(cli.all).await {
+                Ok(devs) if json => list::print_json(&devs, &output),
+                Ok(devs) if !output.is_empty() => list::print_table(&devs, &output),
                 Ok(devs) => {
                     for d in devs {
                         println!("{}", utils::device_display(&d));
+                        println!("    {}", utils::device_identifiers(&d).join(", "));
                     }
                 }
                 Err(e) => {
@@ -143,68 +547,325 @@ impl Cli {
                 quick,
                 size,
                 table,
+                part_type,
+                part_name,
+                bootable,
+                hidden,
+                uefi_boot,
+                data_partition,
+                esp_size,
+                align,
+                offset,
+                targets,
+                yes,
+                force,
+                sd_spec,
+                events,
+                advanced,
             } => {
-                let fs = match fs {
-                    Some(f) => f,
-                    None => {
-                        let supported = utils::detect_supported_fs();
-                        utils::default_fs(&supported).unwrap_or("vfat").to_string()
+                let json = events == EventFormat::Json;
+                let stream = json.then(JsonReporter::default);
+                if json && !yes && !cli.dry_run {
+                    fail(
+                        stream.as_ref(),
+                        2,
+                        "Invalid options: --events json needs --yes, as the confirmation prompt would mix with the events",
+                    );
+                }
+                let Some(esp_size) = esp_size.checked_mul(1024 * 1024) else {
+                    fail(
+                        stream.as_ref(),
+                        2,
+                        "Invalid options: --esp-size is too large",
+                    );
+                };
+                let boot_preset = uefi_boot.then_some(BootPreset {
+                    esp_size,
+                    data_partition,
+                });
+                let devs = list_devices(&backend, cli.all, stream.as_ref()).await;
+                let target = require_device(&devs, &path, cli.all, stream.as_ref());
+                if let Some(why) = &target.system_use {
+                    fail(
+                        stream.as_ref(),
+                        2,
+                        &format!(
+                            "Refusing to format {}: its disk {why}, and the running system depends on it",
+                            target.dev_path
+                        ),
+                    );
+                }
+                let sd_layout = if sd_spec {
+                    match utils::sd_layout(&target) {
+                        Ok(l) => {
+                            note(json, &format!("SD spec layout: {l}"));
+                            Some(l)
+                        }
+                        Err(err) => {
+                            fail(stream.as_ref(), 2, &format!("Invalid target: {err}"));
+                        }
+                    }
+                } else {
+                    None
+                };
+                let targets: Vec<Target> = targets
+                    .iter()
+                    .map(|t| t.parse().expect("restricted to known targets by clap"))
+                    .collect();
+                let advice = if targets.is_empty() {
+                    None
+                } else {
+                    let supported = utils::detect_supported_fs();
+                    match compat::advise(&targets, &supported, Some(&target)) {
+                        Some(a) => Some(a),
+                        None => {
+                            fail(stream.as_ref(), 2, "No filesystem formatting tools found");
+                        }
+                    }
+                };
+                let fs = if let Some(l) = &sd_layout {
+                    l.fs()
+                } else {
+                    match fs {
+                        Some(f) => f.parse().expect("restricted to known filesystems by clap"),
+                        None if boot_preset.is_some_and(|p| !p.data_partition) => Filesystem::Vfat,
+                        None => match &advice {
+                            Some(a) => a.fs,
+                            None => {
+                                let supported = utils::detect_supported_fs();
+                                utils::default_fs(&supported).unwrap_or(Filesystem::Vfat)
+                            }
+                        },
                     }
                 };
+                if let Some(a) = &advice {
+                    let names: Vec<&str> = targets.iter().map(|t| t.name()).collect();
+                    note(
+                        json,
+                        &format!(
+                            "Suggested for {}: {} on {}",
+                            names.join(", "),
+                            a.fs,
+                            a.table
+                        ),
+                    );
+                    let warnings = if a.fs == fs {
+                        a.warnings.clone()
+                    } else {
+                        compat::warnings(&targets, fs, target.size_bytes)
+                    };
+                    for w in warnings {
+                        eprintln!("Warning: {w}");
+                    }
+                }
 
-                let size = utils::parse_size_choice_label(size.as_deref());
-                let partition_table = map_partition_table_opt(table);
+                let size = if let Some(l) = &sd_layout {
+                    Some(l.alloc_unit())
+                } else {
+                    match size {
+                        Some(s) => utils::parse_size_choice_label(Some(&s)),
+                        None => {
+                            let unit = match &advice {
+                                Some(a) if a.fs == fs => a.alloc_unit,
+                                _ => utils::recommended_alloc_unit(fs, &target),
+                            };
+                            if let Some(unit) = unit {
+                                note(
+                                    json,
+                                    &format!(
+                                        "Using recommended allocation unit: {}",
+                                        utils::size_choice_label(fs, unit)
+                                    ),
+                                );
+                            }
+                            unit
+                        }
+                    }
+                };
+                let partition_table = if sd_spec {
+                    Some(PartitionTable::Dos)
+                } else {
+                    map_partition_table_opt(table).or(advice.as_ref().map(|a| a.table))
+                };
 
-                let opts =
+                let mut opts =
                     match utils::build_format_options(fs, label, quick, size, partition_table) {
                         Ok(o) => o,
                         Err(err) => {
-                            eprintln!("Invalid label: {err}");
-                            process::exit(2);
+                            fail(stream.as_ref(), 2, &format!("Invalid options: {err}"));
                         }
                     };
-
-                let be = backend.clone();
-                let path_clone = path.clone();
-                let fmt = tokio::spawn(async move { be.format(&path_clone, opts).await });
-
-                let mut done = false;
-                let mut reporter = ConsoleReporter;
-
-                while !done {
-                    match rx.recv_timeout(Duration::from_millis(50)) {
-                        Ok(msg) => match msg {
-                            Msg::Status(s) => reporter.status(&s),
-                            Msg::Progress(ev) => {
-                                reporter.progress(&ev);
-                                if let ProgressEvent::Completed(_) = ev {
-                                    done = true;
-                                }
-                            }
-                            #[cfg(feature = "gui")]
-                            _ => {}
+                opts.partition_type = match utils::parse_partition_type(&part_type) {
+                    Ok(t) => t,
+                    Err(err) => {
+                        fail(
+                            stream.as_ref(),
+                            2,
+                            &format!("Invalid partition type: {err}"),
+                        );
+                    }
+                };
+                if let Some(name) = &part_name
+                    && let Some(err) = utils::validate_partition_name(name, opts.partition_table)
+                {
+                    fail(
+                        stream.as_ref(),
+                        2,
+                        &format!("Invalid partition name: {err}"),
+                    );
+                }
+                opts.partition_name = part_name;
+                opts.partition_flags.bootable = bootable;
+                opts.partition_flags.hidden = hidden;
+                opts.sd_spec = sd_spec;
+                opts.expected = Some(target.fingerprint());
+                if let Some(a) = &align {
+                    opts.alignment = match utils::parse_alignment(a) {
+                        Ok(a) => a,
+                        Err(err) => {
+                            fail(stream.as_ref(), 2, &format!("Invalid alignment: {err}"));
+                        }
+                    };
+                }
+                if let Some(o) = &offset {
+                    opts.partition_offset = match utils::parse_byte_size(o) {
+                        Ok(o) => Some(o),
+                        Err(err) => {
+                            fail(stream.as_ref(), 2, &format!("Invalid offset: {err}"));
+                        }
+                    };
+                }
+                if let Some(err) = utils::validate_partition_layout(&opts) {
+                    fail(
+                        stream.as_ref(),
+                        2,
+                        &format!("Invalid partition layout: {err}"),
+                    );
+                }
+                opts.advanced = match advanced.into_options(opts.fs) {
+                    Ok(adv) => adv,
+                    Err(err) => {
+                        fail(stream.as_ref(), 2, &format!("Invalid options: {err}"));
+                    }
+                };
+                if let Some(err) = opts.fs.validate_advanced(&opts.advanced) {
+                    fail(stream.as_ref(), 2, &format!("Invalid options: {err}"));
+                }
+                if let Some(preset) = &boot_preset
+                    && let Some(err) = utils::validate_boot_preset(&opts, preset)
+                {
+                    fail(stream.as_ref(), 2, &format!("Invalid preset: {err}"));
+                }
+                opts.boot_preset = boot_preset;
+                if let Some(size) = target.size_bytes
+                    && let Some(err) = opts.fs.validate_volume_size(size)
+                {
+                    fail(stream.as_ref(), 2, &format!("Invalid target: {err}"));
+                }
+                let mut warnings = utils::member_warnings(&target, &devs);
+                warnings.extend(utils::preflight_warnings(&opts, &target));
+                for w in &warnings {
+                    eprintln!("Warning: {w}");
+                }
+                for n in utils::preflight_notes(&opts, &target) {
+                    eprintln!("Note: {n}");
+                }
+                if !warnings.is_empty() && !force {
+                    if !cli.dry_run {
+                        fail(
+                            stream.as_ref(),
+                            2,
+                            "Not formatting; pass --force to go ahead anyway",
+                        );
+                    }
+                    eprintln!("Note: a real run stops here unless --force is given");
+                }
+                if cli.dry_run {
+                    match backend.plan_format(&target.object_path, &opts).await {
+                        Ok(steps) => match &stream {
+                            Some(stream) => stream.plan(&target.dev_path, &steps),
+                            None => print_plan(&target.dev_path, &steps),
                         },
-                        Err(crossbeam_channel::RecvTimeoutError::Timeout) => {}
-                        Err(crossbeam_channel::RecvTimeoutError::Disconnected) => break,
+                        Err(e) => fail(stream.as_ref(), 1, &format!("Format would fail: {e}")),
                     }
+                    return Ok(());
+                }
+                if !yes {
+                    confirm_format(&target, &devs);
+                }
+
+                let be = backend.clone();
+                let obj_path = target.object_path.clone();
+                let fmt = tokio::spawn(async move { be.format(&obj_path, opts).await });
 
-                    if done || fmt.is_finished() {
-                        break;
+                let Some(mut reporter) = stream else {
+                    report_progress(&rx, &fmt, &mut ConsoleReporter);
+                    match fmt.await {
+                        Ok(Ok(new_path)) => println!("Ready: {}", new_path),
+                        Ok(Err(e)) => {
+                            eprintln!("Format failed: {e}");
+                            process::exit(1);
+                        }
+                        Err(join_err) => {
+                            eprintln!("Format task failed to join: {join_err}");
+                            process::exit(1);
+                        }
                     }
+                    return Ok(());
+                };
+                // Layouts with several partitions run one job each; keep
+                // streaming past the first job's completion.
+                while !fmt.is_finished() {
+                    report_progress(&rx, &fmt, &mut reporter);
                 }
-
-                match fmt.await {
-                    Ok(Ok(new_path)) => println!("Ready: {}", new_path),
-                    Ok(Err(e)) => {
-                        eprintln!("Format failed: {e}");
-                        process::exit(1);
+                let outcome = fmt.await;
+                while let Ok(msg) = rx.try_recv() {
+                    match msg {
+                        Msg::Status(s) => reporter.status(&s),
+                        Msg::Progress(ev) => reporter.progress(&ev),
+                        #[cfg(feature = "gui")]
+                        _ => {}
                     }
-                    Err(join_err) => {
-                        eprintln!("Format task failed to join: {join_err}");
+                }
+                match outcome {
+                    Ok(Ok(new_path)) => reporter.result(Ok(&new_path)),
+                    Ok(Err(e)) => fail(Some(&reporter), 1, &format!("Format failed: {e}")),
+                    Err(join_err) => fail(
+                        Some(&reporter),
+                        1,
+                        &format!("Format task failed to join: {join_err}"),
+                    ),
+                }
+            }
+            Command::Label { path, label } => {
+                let devs = list_devices(&backend, cli.all, None).await;
+                let dev = require_device(&devs, &path, cli.all, None);
+                if let Err(err) = utils::validate_relabel(&dev, &label) {
+                    eprintln!("Invalid label: {err}");
+                    process::exit(2);
+                }
+                if cli.dry_run {
+                    let step = format!("Set the label of {} to \"{label}\"", dev.dev_path);
+                    print_plan(&dev.dev_path, &[step]);
+                    return Ok(());
+                }
+                match backend.set_label(&dev.object_path, &label).await {
+                    Ok(()) => println!("Renamed {} to \"{}\"", dev.dev_path, label),
+                    Err(e) => {
+                        eprintln!("Rename failed: {e}");
                         process::exit(1);
                     }
                 }
             }
+            Command::Check { path } => {
+                run_fsck(&backend, &rx, &path, false, cli.all, cli.dry_run).await
+            }
+            Command::Repair { path } => {
+                run_fsck(&backend, &rx, &path, true, cli.all, cli.dry_run).await
+            }
+            Command::Cancel { job_id } if cli.dry_run => {
+                println!("Would request cancellation of job {job_id} (dry run)");
+            }
             Command::Cancel { job_id } => match backend.cancel(&job_id).await {
                 Ok(()) => println!("Cancellation requested for job {job_id}"),
                 Err(e) => {
@@ -3,6 +3,7 @@ use crate::backends::udisks::UdisksBackend;
 use crate::backends::{Backend, ProgressEvent};
 #[cfg(feature = "gui")]
 use crate::backends::{BlockDevice, FormatOptions};
+use crate::config::ConfigManager;
 use std::sync::Arc;
 
 #[derive(Clone)]
@@ -12,9 +13,22 @@ pub(crate) enum Msg {
     #[cfg(feature = "gui")]
     Start {
         obj_path: String,
-        opts: FormatOptions,
+        opts: Box<FormatOptions>,
     },
     #[cfg(feature = "gui")]
+    Rename {
+        obj_path: String,
+        label: String,
+    },
+    #[cfg(feature = "gui")]
+    Fsck {
+        obj_path: String,
+        repair: bool,
+    },
+    /// Whether to list fixed drives on external buses too.
+    #[cfg(feature = "gui")]
+    ShowAll(bool),
+    #[cfg(feature = "gui")]
     Cancel,
     #[cfg(feature = "gui")]
     RequestClose,
@@ -26,17 +40,18 @@ pub(crate) async fn make_backend(
     tx: crossbeam_channel::Sender<Msg>,
     use_mock: bool,
 ) -> Arc<dyn Backend> {
+    let safety = ConfigManager::default().get_safety();
     if use_mock {
-        Arc::new(MockBackend::new(tx))
+        Arc::new(MockBackend::new(tx, safety))
     } else {
-        match UdisksBackend::new(tx.clone()).await {
+        match UdisksBackend::new(tx.clone(), safety.clone()).await {
             Ok(u) => Arc::new(u),
             Err(e) => {
                 eprintln!("Warning: Failed to connect to UDisks2: {}", e);
                 eprintln!(
                     "Falling back to mock backend (no actual disk operations will be performed)"
                 );
-                Arc::new(MockBackend::new(tx))
+                Arc::new(MockBackend::new(tx, safety))
             }
         }
     }
@@ -78,3 +93,71 @@ impl ProgressReporter for ConsoleReporter {
         }
     }
 }
+
+/// Writes one JSON object per line on stdout (`--events json`), tagged with
+/// the current job and phase. The phase is the last step announced by a
+/// message ending in "...", such as "Creating partition table".
+#[derive(Default)]
+pub(crate) struct JsonReporter {
+    job: Option<String>,
+    phase: Option<String>,
+}
+
+impl JsonReporter {
+    fn emit(&self, event: &str, fields: serde_json::Value) {
+        let mut obj = serde_json::json!({
+            "event": event,
+            "job": self.job,
+            "phase": self.phase,
+        });
+        if let (Some(obj), serde_json::Value::Object(fields)) = (obj.as_object_mut(), fields) {
+            obj.extend(fields);
+        }
+        println!("{obj}");
+    }
+
+    /// The steps a dry run would take.
+    pub(crate) fn plan(&self, dev: &str, steps: &[String]) {
+        self.emit("plan", serde_json::json!({ "device": dev, "steps": steps }));
+    }
+
+    /// The outcome of the whole operation, always the last line: the device
+    /// ready for use, or the error with the exit status diskfmt ends with.
+    pub(crate) fn result(&self, outcome: Result<&str, (i32, &str)>) {
+        let fields = match outcome {
+            Ok(dev) => serde_json::json!({ "ok": true, "device": dev }),
+            Err((code, err)) => serde_json::json!({ "ok": false, "code": code, "error": err }),
+        };
+        self.emit("result", fields);
+    }
+}
+
+impl ProgressReporter for JsonReporter {
+    fn status(&mut self, msg: &str) {
+        self.emit("status", serde_json::json!({ "message": msg }));
+    }
+    fn progress(&mut self, ev: &ProgressEvent) {
+        match ev {
+            ProgressEvent::JobStarted(id) => {
+                self.job = Some(id.clone());
+                self.emit("started", serde_json::json!({}));
+            }
+            ProgressEvent::Percent(p) => self.emit("percent", serde_json::json!({ "percent": p })),
+            ProgressEvent::RateBytesPerSec(r) => {
+                self.emit("rate", serde_json::json!({ "bytes_per_sec": r }))
+            }
+            ProgressEvent::Message(m) => {
+                if let Some(step) = m.strip_suffix("...") {
+                    self.phase = Some(step.to_string());
+                }
+                self.emit("message", serde_json::json!({ "message": m }));
+            }
+            ProgressEvent::Completed(Ok(())) => {
+                self.emit("completed", serde_json::json!({ "ok": true }))
+            }
+            ProgressEvent::Completed(Err(e)) => {
+                self.emit("completed", serde_json::json!({ "ok": false, "error": e }))
+            }
+        }
+    }
+}
@@ -0,0 +1,306 @@
+//! Which filesystems the devices a drive is meant for can use, and the
+//! filesystem, partition table and allocation unit that suit all of them.
+
+use crate::backends::filesystem::{GIB, TIB};
+use crate::backends::{BlockDevice, Filesystem, PartitionTable};
+use crate::utils::recommended_alloc_unit;
+use std::fmt;
+use std::str::FromStr;
+
+/// Something a drive will be used with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Target {
+    Windows,
+    Macos,
+    Linux,
+    Tv,
+    Camera,
+    Console,
+}
+
+/// How well a target handles a filesystem.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Support {
+    Full,
+    /// Works with a caveat.
+    Limited(&'static str),
+    None,
+}
+
+impl Target {
+    pub(crate) const ALL: [Target; 6] = [
+        Target::Windows,
+        Target::Macos,
+        Target::Linux,
+        Target::Tv,
+        Target::Camera,
+        Target::Console,
+    ];
+
+    /// Name used on the command line.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Target::Windows => "windows",
+            Target::Macos => "macos",
+            Target::Linux => "linux",
+            Target::Tv => "tv",
+            Target::Camera => "camera",
+            Target::Console => "console",
+        }
+    }
+
+    pub(crate) fn names() -> impl Iterator<Item = &'static str> {
+        Self::ALL.into_iter().map(Self::name)
+    }
+
+    /// Name shown in the GUI.
+    pub(crate) fn description(self) -> &'static str {
+        match self {
+            Target::Windows => "Windows PCs",
+            Target::Macos => "Macs",
+            Target::Linux => "Linux PCs",
+            Target::Tv => "TVs and media players",
+            Target::Camera => "Cameras",
+            Target::Console => "Game consoles",
+        }
+    }
+
+    /// How the target handles `fs` on a volume of `size` bytes, if known.
+    fn support(self, fs: Filesystem, size: Option<u64>) -> Support {
+        let over_32g = size.is_some_and(|s| s > 32 * GIB);
+        match (self, fs) {
+            (Target::Linux, Filesystem::Swap) => Support::Full,
+            (_, Filesystem::Swap) => Support::None,
+            (Target::Linux, Filesystem::HfsPlus) => {
+                Support::Limited("Linux writes HFS+ only without journaling")
+            }
+            (Target::Linux, _) => Support::Full,
+            (Target::Windows, Filesystem::Vfat | Filesystem::Exfat | Filesystem::Ntfs) => {
+                Support::Full
+            }
+            (Target::Windows, Filesystem::Udf) => Support::Full,
+            (Target::Windows, _) => Support::None,
+            (Target::Macos, Filesystem::Vfat | Filesystem::Exfat | Filesystem::HfsPlus) => {
+                Support::Full
+            }
+            (Target::Macos, Filesystem::Udf) => Support::Full,
+            (Target::Macos, Filesystem::Ntfs) => Support::Limited("macOS only reads NTFS"),
+            (Target::Macos, _) => Support::None,
+            (Target::Tv, Filesystem::Vfat) => Support::Full,
+            (Target::Tv, Filesystem::Exfat) => Support::Limited("older TVs don't read exFAT"),
+            (Target::Tv, Filesystem::Ntfs) => {
+                Support::Limited("many TVs play from NTFS but can't record to it")
+            }
+            (Target::Tv, _) => Support::None,
+            // SDHC cards (up to 32 GB) are FAT32, SDXC cards exFAT.
+            (Target::Camera, Filesystem::Vfat) if over_32g => {
+                Support::Limited("cameras expect exFAT on cards over 32 GB")
+            }
+            (Target::Camera, Filesystem::Exfat) if !over_32g => {
+                Support::Limited("cameras expect FAT32 on cards up to 32 GB")
+            }
+            (Target::Camera, Filesystem::Vfat | Filesystem::Exfat) => Support::Full,
+            (Target::Camera, _) => Support::None,
+            (Target::Console, Filesystem::Vfat | Filesystem::Exfat) => Support::Full,
+            (Target::Console, Filesystem::Ntfs) => Support::Limited("only Xbox consoles read NTFS"),
+            (Target::Console, _) => Support::None,
+        }
+    }
+
+    /// Appliances that only understand MBR.
+    fn needs_mbr(self) -> bool {
+        matches!(self, Target::Tv | Target::Camera | Target::Console)
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|t| t.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown target '{s}'"))
+    }
+}
+
+/// What the advisor suggests for a set of targets.
+#[derive(Clone, Debug)]
+pub(crate) struct Advice {
+    pub(crate) fs: Filesystem,
+    pub(crate) table: PartitionTable,
+    /// Allocation unit in the filesystem's unit; `None` leaves it to mkfs.
+    pub(crate) alloc_unit: Option<u64>,
+    /// Targets the filesystem doesn't fully suit.
+    pub(crate) warnings: Vec<String>,
+}
+
+/// Why `fs` doesn't fully suit each of `targets`.
+pub(crate) fn warnings(targets: &[Target], fs: Filesystem, size: Option<u64>) -> Vec<String> {
+    targets
+        .iter()
+        .filter_map(|&t| match t.support(fs, size) {
+            Support::Full => None,
+            Support::Limited(why) => Some(format!("{}: {why}", t.description())),
+            Support::None => Some(format!("{}: can't use {fs}", t.description())),
+        })
+        .collect()
+}
+
+/// Picks the installed filesystem that suits `targets` best on `dev`, in
+/// [`Filesystem::PREFERENCE`] order among equals. `None` if nothing is
+/// installed.
+pub(crate) fn advise(
+    targets: &[Target],
+    supported: &[Filesystem],
+    dev: Option<&BlockDevice>,
+) -> Option<Advice> {
+    let size = dev.and_then(|d| d.size_bytes);
+    let score = |fs: Filesystem| {
+        let (mut none, mut limited) = (0, 0);
+        for t in targets {
+            match t.support(fs, size) {
+                Support::Full => {}
+                Support::Limited(_) => limited += 1,
+                Support::None => none += 1,
+            }
+        }
+        (none, limited)
+    };
+    let fits = |fs: &Filesystem| size.is_none_or(|s| fs.validate_volume_size(s).is_none());
+    let fs = Filesystem::PREFERENCE
+        .into_iter()
+        .filter(|fs| supported.contains(fs))
+        .filter(fits)
+        .min_by_key(|&fs| score(fs))?;
+    let mut warnings = warnings(targets, fs, size);
+    if let Some(best) = Filesystem::PREFERENCE
+        .into_iter()
+        .filter(fits)
+        .min_by_key(|&fs| score(fs))
+        && score(best) < score(fs)
+    {
+        warnings.push(format!(
+            "{best} would suit better; install {} to use it",
+            best.info().mkfs_tools.join(" or ")
+        ));
+    }
+    let table = if !targets.iter().any(|t| t.needs_mbr()) {
+        PartitionTable::Gpt
+    } else if size.is_some_and(|s| s > 2 * TIB) {
+        warnings.push(
+            "MBR can't address more than 2 TiB, using GPT; some appliances may not read it"
+                .to_string(),
+        );
+        PartitionTable::Gpt
+    } else {
+        PartitionTable::Dos
+    };
+    Some(Advice {
+        fs,
+        table,
+        alloc_unit: dev.and_then(|d| recommended_alloc_unit(fs, d)),
+        warnings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_FS: [Filesystem; 6] = [
+        Filesystem::Exfat,
+        Filesystem::Vfat,
+        Filesystem::Ext4,
+        Filesystem::Ntfs,
+        Filesystem::Xfs,
+        Filesystem::Btrfs,
+    ];
+
+    fn stick(size: u64) -> BlockDevice {
+        BlockDevice {
+            dev_path: "/dev/sdx".into(),
+            object_path: String::new(),
+            fs_type: None,
+            label: None,
+            size_bytes: Some(size),
+            vendor_model: None,
+            is_partition: false,
+            removable: true,
+            bus: Some("usb".into()),
+            sd_card: false,
+            partition_name: None,
+            partition_offset: None,
+            table_object: None,
+            disk_path: None,
+            mount_points: Vec::new(),
+            uuid: None,
+            serial: None,
+            wwn: None,
+            links: Vec::new(),
+            member_of: None,
+            system_use: None,
+        }
+    }
+
+    #[test]
+    fn prefers_the_filesystem_every_target_reads() {
+        let targets = [Target::Windows, Target::Macos, Target::Tv];
+        let advice = advise(&targets, &ALL_FS, Some(&stick(16 * GIB))).unwrap();
+        assert_eq!(advice.fs, Filesystem::Vfat);
+        assert_eq!(advice.table, PartitionTable::Dos);
+        assert!(advice.warnings.is_empty());
+    }
+
+    #[test]
+    fn picks_exfat_for_cameras_on_sdxc_cards() {
+        let advice = advise(&[Target::Camera], &ALL_FS, Some(&stick(64 * GIB))).unwrap();
+        assert_eq!(advice.fs, Filesystem::Exfat);
+        let advice = advise(&[Target::Camera], &ALL_FS, Some(&stick(16 * GIB))).unwrap();
+        assert_eq!(advice.fs, Filesystem::Vfat);
+    }
+
+    #[test]
+    fn falls_back_to_gpt_over_2_tib() {
+        let advice = advise(&[Target::Tv], &ALL_FS, Some(&stick(3 * TIB))).unwrap();
+        assert_eq!(advice.table, PartitionTable::Gpt);
+        assert!(
+            advice
+                .warnings
+                .iter()
+                .any(|w| w.starts_with("MBR can't address"))
+        );
+    }
+
+    #[test]
+    fn uses_gpt_without_appliances() {
+        let advice = advise(&[Target::Linux], &ALL_FS, None).unwrap();
+        assert_eq!(advice.table, PartitionTable::Gpt);
+        assert_eq!(advice.alloc_unit, None);
+    }
+
+    #[test]
+    fn names_a_better_filesystem_that_isnt_installed() {
+        let targets = [Target::Windows, Target::Macos];
+        let supported = [Filesystem::Ext4, Filesystem::Ntfs];
+        let advice = advise(&targets, &supported, Some(&stick(16 * GIB))).unwrap();
+        assert_eq!(advice.fs, Filesystem::Ntfs);
+        assert!(
+            advice
+                .warnings
+                .iter()
+                .any(|w| w.starts_with("exfat would suit better"))
+        );
+    }
+
+    #[test]
+    fn needs_an_installed_filesystem() {
+        assert!(advise(&[Target::Windows], &[], None).is_none());
+    }
+}
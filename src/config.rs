@@ -1,6 +1,15 @@
+use crate::backends::Fingerprint;
 use crate::style::{self, SchemeOpt, ThemeOpt, parse_scheme, parse_theme};
 use serde::Deserialize;
-use std::{env, fs, io, path::PathBuf, process::Command};
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Read before the user's config; its `[safety]` settings can't be loosened
+/// there.
+const SYSTEM_CONFIG_PATH: &str = "/etc/diskfmt/config.toml";
 
 const CONFIG_TEMPLATE: &str = "\
 # diskfmt configuration
@@ -8,6 +17,14 @@ const CONFIG_TEMPLATE: &str = "\
 [style]
 # theme = \"DARK2\"
 # scheme = \"Fleet1\"
+
+[safety]
+# Devices diskfmt never lists or formats, by serial, WWN, model or
+# /dev/disk/by-* path (a disk's path covers its partitions).
+# protected = [\"WD-WX11A12B3456\", \"/dev/disk/by-id/usb-Generic_STORAGE_DEVICE-0:0\"]
+# Kiosk mode: only these devices (e.g. card reader slots by by-path) are
+# listed and formatted.
+# allowed = [\"/dev/disk/by-path/pci-0000:00:14.0-usb-0:2:1.0-scsi-0:0:0:0\"]
 ";
 
 #[derive(Debug, Deserialize)]
@@ -16,9 +33,70 @@ pub(crate) struct StyleConfig {
     scheme: Option<String>,
 }
 
+/// Which devices diskfmt may touch.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub(crate) struct SafetyConfig {
+    #[serde(default)]
+    protected: Vec<String>,
+    /// When set, nothing else is listed or formatted.
+    allowed: Option<Vec<String>>,
+}
+
+impl SafetyConfig {
+    /// Why the policy keeps diskfmt away from the device with `fp` and
+    /// `links`, if it does.
+    pub(crate) fn refusal(&self, fp: &Fingerprint, links: &[String]) -> Option<&'static str> {
+        if self.protected.iter().any(|e| device_matches(e, fp, links)) {
+            return Some("is protected by the [safety] config");
+        }
+        match &self.allowed {
+            Some(allowed) if !allowed.iter().any(|e| device_matches(e, fp, links)) => {
+                Some("is not allowed by the [safety] config")
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Whether `entry`, a by-* path or a serial, WWN or model, names the device.
+fn device_matches(entry: &str, fp: &Fingerprint, links: &[String]) -> bool {
+    if entry.starts_with('/') {
+        return links.iter().any(|l| {
+            l.strip_prefix(entry)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("-part"))
+        });
+    }
+    [&fp.serial, &fp.wwn, &fp.model]
+        .into_iter()
+        .flatten()
+        .any(|v| v.eq_ignore_ascii_case(entry))
+}
+
+fn read_safety(path: &Path) -> SafetyConfig {
+    let Ok(contents) = fs::read_to_string(path) else {
+        return SafetyConfig::default();
+    };
+    match toml::from_str::<FileConfig>(&contents) {
+        Ok(c) => c.safety.unwrap_or_default(),
+        Err(e) => {
+            // A policy that can't be read mustn't open everything up.
+            eprintln!(
+                "Could not read [safety] from {}: {}; no devices will be offered",
+                path.display(),
+                e
+            );
+            SafetyConfig {
+                protected: Vec::new(),
+                allowed: Some(Vec::new()),
+            }
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub(crate) struct FileConfig {
     style: Option<StyleConfig>,
+    safety: Option<SafetyConfig>,
 }
 
 pub struct ConfigOpts {
@@ -142,6 +220,20 @@ impl ConfigManager {
         (theme, scheme)
     }
 
+    /// The system-wide `[safety]` settings merged with the user's: protected
+    /// devices add up, the system allow-list wins over the user's.
+    pub(crate) fn get_safety(&self) -> SafetyConfig {
+        let system = read_safety(Path::new(SYSTEM_CONFIG_PATH));
+        let user = self
+            .resolved_path()
+            .map(|p| read_safety(&p))
+            .unwrap_or_default();
+        SafetyConfig {
+            protected: [system.protected, user.protected].concat(),
+            allowed: system.allowed.or(user.allowed),
+        }
+    }
+
     pub(crate) fn write_default(&self, overwrite: bool) -> io::Result<()> {
         let Some(path) = self.resolved_path() else {
             return Err(io::Error::new(io::ErrorKind::NotFound, "No config path"));
@@ -191,6 +283,16 @@ impl ConfigManager {
             eprintln!("Missing [style] table");
             ok = false;
         }
+        if let Some(safety) = parsed.safety {
+            let entries = safety
+                .protected
+                .iter()
+                .chain(safety.allowed.iter().flatten());
+            for e in entries.filter(|e| e.starts_with('/') && !e.starts_with("/dev/disk/by-")) {
+                ok = false;
+                eprintln!("Invalid [safety] path '{}'. Use a /dev/disk/by-* path", e);
+            }
+        }
         if ok {
             println!("Config OK");
         }
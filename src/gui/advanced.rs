@@ -0,0 +1,298 @@
+use crate::backends::filesystem::{
+    BTRFS_CHECKSUMS, BTRFS_METADATA_PROFILES, FAT_SIZES, VolumeIdKind,
+};
+use crate::backends::{AdvancedOptions, Filesystem};
+use fltk::{
+    app,
+    button::{Button, CheckButton},
+    frame::Frame,
+    group::Flex,
+    input::Input,
+    menu::Choice,
+    prelude::{ButtonExt, GroupExt, InputExt, MenuExt, WidgetBase, WidgetExt, WindowExt},
+    window::Window,
+};
+use std::{cell::RefCell, rc::Rc};
+
+const ROW_HEIGHT: i32 = 25;
+
+/// Widgets of the options that apply to the filesystem being edited.
+#[derive(Default)]
+struct Fields {
+    reserved: Option<Input>,
+    inode_ratio: Option<Input>,
+    journal: Option<Choice>,
+    features: Option<Input>,
+    fat_size: Option<Choice>,
+    volume_id: Option<Input>,
+    volume_id_seed: Option<Input>,
+    btrfs_metadata: Option<Choice>,
+    btrfs_checksum: Option<Choice>,
+    ntfs_compression: Option<CheckButton>,
+    xfs_reflink: Option<Choice>,
+    xfs_crc: Option<Choice>,
+}
+
+impl Fields {
+    fn read(&self, fs: Filesystem) -> Result<AdvancedOptions, String> {
+        let seed = self
+            .volume_id_seed
+            .as_ref()
+            .map(|i| i.value())
+            .filter(|s| !s.is_empty());
+        let volume_id = match (&self.volume_id, seed) {
+            (_, Some(seed)) => Some(fs.volume_id_from_seed(&seed)?),
+            (Some(i), None) if !i.value().trim().is_empty() => {
+                Some(fs.parse_volume_id(i.value().trim())?)
+            }
+            _ => None,
+        };
+        Ok(AdvancedOptions {
+            reserved_percent: parse_input(&self.reserved, "reserved blocks")?,
+            inode_ratio: parse_input(&self.inode_ratio, "inode ratio")?,
+            journal: self.journal.as_ref().and_then(read_tristate),
+            ext_features: self
+                .features
+                .as_ref()
+                .map(|i| {
+                    i.value()
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|f| !f.is_empty())
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            fat_size: self
+                .fat_size
+                .as_ref()
+                .and_then(|c| usize::try_from(c.value() - 1).ok())
+                .map(|idx| FAT_SIZES[idx]),
+            volume_id,
+            btrfs_metadata: self.btrfs_metadata.as_ref().and_then(read_named),
+            btrfs_checksum: self.btrfs_checksum.as_ref().and_then(read_named),
+            ntfs_compression: self.ntfs_compression.as_ref().is_some_and(|c| c.value()),
+            xfs_reflink: self.xfs_reflink.as_ref().and_then(read_tristate),
+            xfs_crc: self.xfs_crc.as_ref().and_then(read_tristate),
+        })
+    }
+}
+
+fn parse_input<T: std::str::FromStr>(
+    input: &Option<Input>,
+    what: &str,
+) -> Result<Option<T>, String> {
+    match input {
+        Some(i) if !i.value().trim().is_empty() => i
+            .value()
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("invalid {what}: {}", i.value())),
+        _ => Ok(None),
+    }
+}
+
+fn field(col: &mut Flex, label: &str) {
+    let frame = Frame::default().with_label(label);
+    col.fixed(&frame, ROW_HEIGHT);
+}
+
+fn input(col: &mut Flex, label: &str, value: Option<String>) -> Input {
+    field(col, label);
+    let mut i = Input::default();
+    i.set_value(&value.unwrap_or_default());
+    col.fixed(&i, ROW_HEIGHT);
+    i
+}
+
+/// A "Default/On/Off" choice for an optional switch.
+fn tristate(col: &mut Flex, label: &str, value: Option<bool>) -> Choice {
+    field(col, label);
+    let mut c = Choice::default();
+    c.add_choice("Default|On|Off");
+    c.set_value(match value {
+        None => 0,
+        Some(true) => 1,
+        Some(false) => 2,
+    });
+    col.fixed(&c, ROW_HEIGHT);
+    c
+}
+
+fn read_tristate(c: &Choice) -> Option<bool> {
+    match c.value() {
+        1 => Some(true),
+        2 => Some(false),
+        _ => None,
+    }
+}
+
+/// A "Default" entry followed by `names`.
+fn named(col: &mut Flex, label: &str, names: &[&str], value: Option<&str>) -> Choice {
+    field(col, label);
+    let mut c = Choice::default();
+    c.add_choice("Default");
+    for n in names {
+        c.add_choice(n);
+    }
+    let idx = value
+        .and_then(|v| names.iter().position(|n| *n == v))
+        .map_or(0, |i| i + 1);
+    c.set_value(idx as i32);
+    col.fixed(&c, ROW_HEIGHT);
+    c
+}
+
+fn read_named(c: &Choice) -> Option<String> {
+    c.choice().filter(|v| v != "Default")
+}
+
+/// Whether the dialog has anything to offer for `fs`.
+pub(crate) fn has_fields(fs: Filesystem) -> bool {
+    fs.info().volume_id != VolumeIdKind::None
+        || matches!(
+            fs,
+            Filesystem::Ext4
+                | Filesystem::Ext3
+                | Filesystem::Ext2
+                | Filesystem::Vfat
+                | Filesystem::Btrfs
+                | Filesystem::Ntfs
+                | Filesystem::Xfs
+        )
+}
+
+/// Modal dialog editing the advanced options of `fs`. Returns the new options,
+/// or `None` when cancelled.
+pub(crate) fn advanced_dialog(
+    fs: Filesystem,
+    current: &AdvancedOptions,
+) -> Option<AdvancedOptions> {
+    let mut win = Window::default()
+        .with_size(320, 100)
+        .with_label(&format!("Advanced ({fs})"));
+    let mut col = Flex::default_fill().column();
+    col.set_margins(10, 5, 10, 5);
+    col.set_pad(5);
+
+    let mut fields = Fields::default();
+    match fs {
+        Filesystem::Ext4 | Filesystem::Ext3 | Filesystem::Ext2 => {
+            fields.reserved = Some(input(
+                &mut col,
+                "Reserved blocks (%)",
+                current.reserved_percent.map(|v| v.to_string()),
+            ));
+            fields.inode_ratio = Some(input(
+                &mut col,
+                "Bytes per inode",
+                current.inode_ratio.map(|v| v.to_string()),
+            ));
+            if fs == Filesystem::Ext4 {
+                fields.journal = Some(tristate(&mut col, "Journal", current.journal));
+            }
+            fields.features = Some(input(
+                &mut col,
+                "Feature flags (comma-separated, ^ disables)",
+                Some(current.ext_features.join(",")),
+            ));
+        }
+        Filesystem::Vfat => {
+            field(&mut col, "FAT type");
+            let mut c = Choice::default();
+            c.add_choice("Auto");
+            for bits in FAT_SIZES {
+                c.add_choice(&format!("FAT{bits}"));
+            }
+            let idx = current
+                .fat_size
+                .and_then(|b| FAT_SIZES.iter().position(|&x| x == b))
+                .map_or(0, |i| i + 1);
+            c.set_value(idx as i32);
+            col.fixed(&c, ROW_HEIGHT);
+            fields.fat_size = Some(c);
+        }
+        Filesystem::Btrfs => {
+            fields.btrfs_metadata = Some(named(
+                &mut col,
+                "Metadata profile",
+                &BTRFS_METADATA_PROFILES,
+                current.btrfs_metadata.as_deref(),
+            ));
+            fields.btrfs_checksum = Some(named(
+                &mut col,
+                "Checksum",
+                &BTRFS_CHECKSUMS,
+                current.btrfs_checksum.as_deref(),
+            ));
+        }
+        Filesystem::Ntfs => {
+            let mut c = CheckButton::default().with_label("Compression");
+            c.set_value(current.ntfs_compression);
+            col.fixed(&c, ROW_HEIGHT);
+            fields.ntfs_compression = Some(c);
+        }
+        Filesystem::Xfs => {
+            fields.xfs_crc = Some(tristate(
+                &mut col,
+                "Metadata checksums (crc)",
+                current.xfs_crc,
+            ));
+            fields.xfs_reflink = Some(tristate(&mut col, "Reflink", current.xfs_reflink));
+        }
+        _ => {}
+    }
+    if fs.info().volume_id != VolumeIdKind::None {
+        fields.volume_id = Some(input(
+            &mut col,
+            "UUID / volume serial (empty for random)",
+            current.volume_id.clone(),
+        ));
+        fields.volume_id_seed = Some(input(&mut col, "...or derive it from seed", None));
+    }
+
+    let mut row_btn = Flex::default().row();
+    let mut ok_btn = Button::default().with_label("OK");
+    let mut cancel_btn = Button::default().with_label("Cancel");
+    row_btn.set_pad(10);
+    row_btn.end();
+    col.fixed(&row_btn, 30);
+    col.end();
+    win.end();
+
+    let rows = col.children() - 1;
+    win.set_size(320, rows * (ROW_HEIGHT + 5) + 50);
+    win.make_modal(true);
+    win.show();
+
+    let result: Rc<RefCell<Option<AdvancedOptions>>> = Rc::new(RefCell::new(None));
+    ok_btn.set_callback({
+        let result = result.clone();
+        let mut win = win.clone();
+        move |_| {
+            let adv = match fields.read(fs) {
+                Ok(adv) => adv,
+                Err(err) => {
+                    fltk::dialog::message_default(&err);
+                    return;
+                }
+            };
+            if let Some(err) = fs.validate_advanced(&adv) {
+                fltk::dialog::message_default(&err);
+                return;
+            }
+            *result.borrow_mut() = Some(adv);
+            win.hide();
+        }
+    });
+    cancel_btn.set_callback({
+        let mut win = win.clone();
+        move |_| win.hide()
+    });
+
+    while win.shown() {
+        app::wait();
+    }
+    result.take()
+}
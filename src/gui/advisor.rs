@@ -0,0 +1,73 @@
+use crate::compat::Target;
+use fltk::{
+    app,
+    button::{Button, CheckButton},
+    frame::Frame,
+    group::Flex,
+    prelude::{ButtonExt, GroupExt, WidgetBase, WidgetExt, WindowExt},
+    window::Window,
+};
+use std::{cell::RefCell, rc::Rc};
+
+const ROW_HEIGHT: i32 = 25;
+
+/// Modal "What will I use this with?" dialog. Returns the ticked targets, or
+/// `None` when cancelled.
+pub(crate) fn targets_dialog(current: &[Target]) -> Option<Vec<Target>> {
+    let mut win = Window::default()
+        .with_size(300, 100)
+        .with_label("What will I use this with?");
+    let mut col = Flex::default_fill().column();
+    col.set_margins(10, 5, 10, 5);
+    col.set_pad(5);
+
+    let frame = Frame::default().with_label("Tick everything the drive should work with:");
+    col.fixed(&frame, ROW_HEIGHT);
+    let checks: Vec<(Target, CheckButton)> = Target::ALL
+        .into_iter()
+        .map(|t| {
+            let mut c = CheckButton::default().with_label(t.description());
+            c.set_value(current.contains(&t));
+            col.fixed(&c, ROW_HEIGHT);
+            (t, c)
+        })
+        .collect();
+
+    let mut row_btn = Flex::default().row();
+    let mut ok_btn = Button::default().with_label("OK");
+    let mut cancel_btn = Button::default().with_label("Cancel");
+    row_btn.set_pad(10);
+    row_btn.end();
+    col.fixed(&row_btn, 30);
+    col.end();
+    win.end();
+
+    let rows = col.children() - 1;
+    win.set_size(300, rows * (ROW_HEIGHT + 5) + 50);
+    win.make_modal(true);
+    win.show();
+
+    let result: Rc<RefCell<Option<Vec<Target>>>> = Rc::new(RefCell::new(None));
+    ok_btn.set_callback({
+        let result = result.clone();
+        let mut win = win.clone();
+        move |_| {
+            let targets = checks
+                .iter()
+                .filter(|(_, c)| c.value())
+                .map(|&(t, _)| t)
+                .collect();
+            *result.borrow_mut() = Some(targets);
+            win.hide();
+        }
+    });
+    cancel_btn.set_callback({
+        let mut win = win.clone();
+        move |_| win.hide()
+    });
+
+    while win.shown() {
+        app::wait();
+    }
+    result.take()
+}
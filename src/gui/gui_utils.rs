@@ -1,8 +1,22 @@
-use crate::backends::{PartitionTable, ProgressEvent};
+use crate::backends::filesystem::AllocUnit;
+use crate::backends::{
+    Alignment, BlockDevice, Filesystem, PartitionTable, PartitionType, ProgressEvent,
+};
 use crate::common::Msg;
 use crate::common::UiSender;
+use crate::utils::{recommended_alloc_unit, size_choice_label};
 use fltk::{menu::Choice, prelude::MenuExt};
 
+pub(crate) const PARTITION_TYPE_CHOICES: [&str; 4] = [
+    "Auto type",
+    "EFI System",
+    "Microsoft basic data",
+    "Linux filesystem",
+];
+
+pub(crate) const ALIGNMENT_CHOICES: [&str; 4] =
+    ["1 MiB (default)", "4 MiB", "16 MiB", "Optimal I/O size"];
+
 pub(crate) fn report_error(
     tx: crossbeam_channel::Sender<Msg>,
     operation: &str,
@@ -13,52 +27,119 @@ pub(crate) fn report_error(
     tx.emit(Msg::Progress(ProgressEvent::Completed(Err(err))));
 }
 
-pub(crate) fn fill_size_choices(choice: &mut Choice, fs: Option<&str>) {
+/// The filesystem picked in `choice`, whose entries are `supported` in order.
+pub(crate) fn selected_fs(supported: &[Filesystem], choice: &Choice) -> Filesystem {
+    usize::try_from(choice.value())
+        .ok()
+        .and_then(|idx| supported.get(idx).copied())
+        .unwrap_or(Filesystem::Vfat)
+}
+
+/// Fills the allocation unit choices for `fs`, marking and selecting the one
+/// recommended for `dev`.
+pub(crate) fn fill_size_choices(choice: &mut Choice, fs: Filesystem, dev: Option<&BlockDevice>) {
     choice.clear();
     choice.add_choice("Auto");
-    match fs.unwrap_or("vfat") {
-        "vfat" => {
-            for spc in [1_u64, 2, 4, 8, 16, 32, 64, 128] {
-                let unit = if spc == 1 { "sector" } else { "sectors" };
-                choice.add_choice(&format!("{} {}", spc, unit));
-            }
-        }
-        "exfat" | "ntfs" => {
-            for sz in [
-                4096_u64, 8192, 16384, 32768, 65536, 131072, 262144, 524288, 1048576,
-            ] {
-                choice.add_choice(&format!("{} bytes", sz));
-            }
-        }
-        "ext4" | "xfs" => {
-            for sz in [1024_u64, 2048, 4096] {
-                choice.add_choice(&format!("{} bytes", sz));
-            }
-        }
-        "btrfs" => {
-            for sz in [4096_u64, 16384, 32768, 65536] {
-                choice.add_choice(&format!("{} bytes", sz));
-            }
+    let recommended = dev.and_then(|d| recommended_alloc_unit(fs, d));
+    let units = match fs.info().alloc_unit {
+        AllocUnit::Sectors(units) | AllocUnit::Bytes(units) => units,
+        AllocUnit::None => &[],
+    };
+    let mut selected = 0;
+    for (i, &unit) in units.iter().enumerate() {
+        let label = size_choice_label(fs, unit);
+        if recommended == Some(unit) {
+            choice.add_choice(&format!("{label} (recommended)"));
+            selected = i as i32 + 1;
+        } else {
+            choice.add_choice(&label);
         }
-        _ => {}
     }
-    choice.set_value(0);
+    choice.set_value(selected);
 }
 
-pub(crate) fn size_label_text(fs: Option<&str>) -> &'static str {
-    match fs.unwrap_or("vfat") {
-        "vfat" => "Sectors per cluster",
-        "exfat" | "ntfs" => "Cluster size (bytes)",
-        "ext4" | "xfs" => "Block size (bytes)",
-        "btrfs" => "Nodesize (bytes)",
-        _ => "Allocation Unit Size",
-    }
+/// The device picked in `choice`, whose entries are `devs` in order.
+pub(crate) fn selected_device<'a>(
+    devs: &'a [BlockDevice],
+    choice: &Choice,
+) -> Option<&'a BlockDevice> {
+    usize::try_from(choice.value())
+        .ok()
+        .and_then(|idx| devs.get(idx))
+}
+
+pub(crate) fn size_label_text(fs: Filesystem) -> &'static str {
+    fs.info().alloc_caption
 }
 
 pub(crate) fn parse_partition_table_choice(choice: Option<&str>) -> Option<PartitionTable> {
     match choice {
         Some(s) if s.starts_with("MBR") => Some(PartitionTable::Dos),
+        Some(s) if s.starts_with("None") => Some(PartitionTable::None),
         Some(_) => Some(PartitionTable::Gpt),
         None => Some(PartitionTable::Gpt),
     }
 }
+
+pub(crate) fn parse_alignment_choice(choice: Option<&str>) -> Alignment {
+    match choice {
+        Some("Optimal I/O size") => Alignment::OptimalIo,
+        Some(s) => crate::utils::parse_byte_size(&s.replace(" (default)", "").replace(' ', ""))
+            .map(Alignment::Bytes)
+            .unwrap_or_default(),
+        None => Alignment::default(),
+    }
+}
+
+/// The partition type choice's tooltip, with what 'Auto' picks for each
+/// filesystem taken from `Filesystem::partition_type`.
+pub(crate) fn partition_type_tooltip() -> String {
+    let mut groups: Vec<(PartitionType, Vec<&str>)> = Vec::new();
+    for fs in Filesystem::ALL {
+        let t = fs.partition_type();
+        match groups.iter_mut().find(|(g, _)| *g == t) {
+            Some((_, names)) => names.push(fs.name()),
+            None => groups.push((t, vec![fs.name()])),
+        }
+    }
+    let mut picks: Vec<String> = groups
+        .iter()
+        .filter(|(t, _)| *t != PartitionType::LinuxFilesystem)
+        .map(|(t, names)| {
+            let names = match names.split_last() {
+                Some((last, rest)) if !rest.is_empty() => {
+                    format!("{} and {last}", rest.join(", "))
+                }
+                _ => names.concat(),
+            };
+            format!("{} for {names}", partition_type_name(t))
+        })
+        .collect();
+    picks.push("Linux filesystem otherwise".to_string());
+    format!(
+        "The partition type recorded in the partition table. 'Auto' picks {}. Some TVs and \
+         firmware only recognize partitions of a specific type; EFI System is needed for UEFI \
+         boot media.",
+        picks.join("; ")
+    )
+}
+
+fn partition_type_name(t: &PartitionType) -> &str {
+    match t {
+        PartitionType::EfiSystem => "EFI System",
+        PartitionType::MicrosoftBasicData => "Microsoft basic data",
+        PartitionType::LinuxFilesystem => "Linux filesystem",
+        PartitionType::LinuxSwap => "Linux swap",
+        PartitionType::AppleHfsPlus => "Apple HFS+",
+        PartitionType::Custom(s) => s,
+    }
+}
+
+pub(crate) fn parse_partition_type_choice(choice: Option<&str>) -> Option<PartitionType> {
+    match choice {
+        Some("EFI System") => Some(PartitionType::EfiSystem),
+        Some("Microsoft basic data") => Some(PartitionType::MicrosoftBasicData),
+        Some("Linux filesystem") => Some(PartitionType::LinuxFilesystem),
+        _ => None,
+    }
+}
@@ -5,6 +5,8 @@ use fltk::{
     app, dialog,
     prelude::{MenuExt, WidgetExt},
 };
+mod advanced;
+mod advisor;
 mod gui_utils;
 mod view;
 use gui_utils::report_error;
@@ -23,6 +25,7 @@ pub struct Ui {
     devices: Rc<RefCell<Vec<BlockDevice>>>,
     tx: crossbeam_channel::Sender<Msg>,
     pub(crate) state: AppState,
+    show_all: bool,
 }
 
 impl ProgressReporter for Ui {
@@ -43,10 +46,16 @@ impl Ui {
             devices,
             tx,
             state: AppState::Idle,
+            show_all: false,
         }
     }
 
     pub(crate) fn update_devices(&mut self, devs: Vec<BlockDevice>) {
+        // Disks the running system depends on are never offered.
+        let devs: Vec<BlockDevice> = devs
+            .into_iter()
+            .filter(|d| d.system_use.is_none())
+            .collect();
         self.view.device_choice.clear();
         for d in &devs {
             let display = crate::utils::device_display(d);
@@ -55,13 +64,8 @@ impl Ui {
         *self.devices.borrow_mut() = devs;
         if self.view.device_choice.size() > 0 {
             self.view.device_choice.set_value(0);
-            let devs = self.devices.borrow();
-            let is_partition = devs.first().map(|d| d.is_partition).unwrap_or(false);
-            if is_partition {
-                self.view.pt_choice.deactivate();
-            } else {
-                self.view.pt_choice.activate();
-            }
+            // Updates the disk options and allocation unit choices.
+            self.view.device_choice.do_callback();
         }
     }
 
@@ -69,14 +73,23 @@ impl Ui {
         match &self.state {
             AppState::Idle => {
                 self.view.start_btn.activate();
+                self.view.rename_btn.activate();
+                self.view.check_btn.activate();
+                self.view.repair_btn.activate();
                 self.view.cancel_btn.deactivate();
             }
             AppState::Starting => {
                 self.view.start_btn.deactivate();
+                self.view.rename_btn.deactivate();
+                self.view.check_btn.deactivate();
+                self.view.repair_btn.deactivate();
                 self.view.cancel_btn.deactivate();
             }
             AppState::Formatting { .. } => {
                 self.view.start_btn.deactivate();
+                self.view.rename_btn.deactivate();
+                self.view.check_btn.deactivate();
+                self.view.repair_btn.deactivate();
                 self.view.cancel_btn.activate();
             }
         }
@@ -133,6 +146,7 @@ impl Ui {
 
     pub(crate) fn handle_msg(&mut self, backend: Arc<dyn Backend>, msg: Msg) {
         let tx = self.tx.clone();
+        let show_all = self.show_all;
         match msg {
             Msg::Devices(devs) => self.update_devices(devs),
             Msg::Status(s) => {
@@ -153,14 +167,14 @@ impl Ui {
                     let tx = tx.clone();
                     let be = backend.clone();
                     async move {
-                        let formatted_path = match be.format(&obj_path, opts).await {
+                        let formatted_path = match be.format(&obj_path, *opts).await {
                             Ok(path) => path,
                             Err(e) => {
                                 report_error(tx.clone(), "Format", e);
                                 return;
                             }
                         };
-                        match be.list_block_devices().await {
+                        match be.list_block_devices(show_all).await {
                             Ok(devs) => {
                                 tx.emit(Msg::Devices(devs));
                                 tx.emit(Msg::Status(format!("Ready: {formatted_path}")));
@@ -172,6 +186,66 @@ impl Ui {
                     }
                 });
             }
+            Msg::Rename { obj_path, label } => {
+                self.set_state(AppState::Starting);
+                self.update_progress(ProgressEvent::Message("Renaming...".into()));
+
+                tokio::spawn({
+                    let tx = tx.clone();
+                    let be = backend.clone();
+                    async move {
+                        if let Err(e) = be.set_label(&obj_path, &label).await {
+                            report_error(tx.clone(), "Rename", e);
+                            return;
+                        }
+                        tx.emit(Msg::Progress(ProgressEvent::Completed(Ok(()))));
+                        match be.list_block_devices(show_all).await {
+                            Ok(devs) => {
+                                tx.emit(Msg::Devices(devs));
+                                tx.emit(Msg::Status(format!("Renamed to \"{label}\"")));
+                            }
+                            Err(e) => {
+                                tx.emit(Msg::Status(format!("Refresh failed: {e}")));
+                            }
+                        }
+                    }
+                });
+            }
+            Msg::Fsck { obj_path, repair } => {
+                self.set_state(AppState::Starting);
+                self.update_progress(ProgressEvent::Percent(0.0));
+
+                tokio::spawn({
+                    let tx = tx.clone();
+                    let be = backend.clone();
+                    async move {
+                        let (op, res) = if repair {
+                            ("Repair", be.repair(&obj_path).await)
+                        } else {
+                            ("Check", be.check(&obj_path).await)
+                        };
+                        match res {
+                            Ok(report) => tx.emit(Msg::Status(match report.note() {
+                                Some(n) => format!("{}\n\nNote: {n}", report.summary()),
+                                None => report.summary().to_string(),
+                            })),
+                            Err(e) => tx.emit(Msg::Status(format!("{op} failed: {e}"))),
+                        }
+                    }
+                });
+            }
+            Msg::ShowAll(all) => {
+                self.show_all = all;
+                tokio::spawn({
+                    let be = backend.clone();
+                    async move {
+                        match be.list_block_devices(all).await {
+                            Ok(devs) => tx.emit(Msg::Devices(devs)),
+                            Err(e) => tx.emit(Msg::Status(format!("List error: {e}"))),
+                        }
+                    }
+                });
+            }
             Msg::Cancel => {
                 if let Some(job_id) = self.active_job_id() {
                     tokio::spawn({
@@ -232,7 +306,7 @@ impl Ui {
             let tx = tx.clone();
             let be = backend.clone();
             async move {
-                match be.list_block_devices().await {
+                match be.list_block_devices(false).await {
                     Ok(devs) => {
                         tx.emit(Msg::Devices(devs));
                     }
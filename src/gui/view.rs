@@ -1,6 +1,9 @@
+use super::advanced::{advanced_dialog, has_fields};
+use super::advisor::targets_dialog;
 use super::gui_utils::*;
-use crate::backends::BlockDevice;
+use crate::backends::{AdvancedOptions, BlockDevice, BootPreset, DEFAULT_ESP_SIZE, PartitionTable};
 use crate::common::{Msg, UiSender};
+use crate::compat::{Target, advise};
 use crate::utils::{default_fs, detect_supported_fs};
 use fltk::{
     app,
@@ -26,13 +29,18 @@ const TOOLTIP_DEVICE_CHOICE: &str = concat!(
     "Be cautious when selecting a device, as formatting will erase all data on it. ",
     "Ensure you choose the correct device to avoid data loss."
 );
+const TOOLTIP_SHOW_ALL: &str = concat!(
+    "Also list fixed drives connected over USB or FireWire, such as USB hard drives and NVMe ",
+    "enclosures. Disks holding the running system are never listed."
+);
 const TOOLTIP_FS_CHOICE: &str = concat!(
-    "Select the filesystem type to format the selected device with. ",
-    "Different filesystems have different features, performance characteristics, and ",
-    "compatibility with various operating systems. Common options include FAT32 ",
-    "(widely compatible), NTFS (Windows), ext4 (Linux), and exFAT (large files and ",
-    "cross-platform). Choose a filesystem that best suits your needs based on the ",
-    "intended use of the device."
+    "Select the filesystem type to format the selected device with. Not sure which one? ",
+    "Use 'Help me choose...'."
+);
+const TOOLTIP_ADVISOR_BTN: &str = concat!(
+    "Tell diskfmt what the drive will be used with (Windows, Mac, TV, camera, ...) and it ",
+    "picks the filesystem, partition table and allocation unit, and warns about devices ",
+    "that won't read it."
 );
 const TOOLTIP_SIZE_CHOICE: &str = concat!(
     "Specifies the cluster size (for FAT filesystems) or block size (for ext filesystems) ",
@@ -54,17 +62,63 @@ const TOOLTIP_PT_CHOICE: &str = concat!(
     "a higher number of partitions (128 vs. 4), and has better data protection with a backup, ",
     "whereas MBR is limited to 2 TB disks and fewer partitions and lacks this redundancy. ",
     "Modern systems use GPT with UEFI firmware, while MBR is limited to legacy BIOS systems. ",
-    "Choose GPT for new installations unless compatibility with very old systems is required."
+    "Choose GPT for new installations unless compatibility with very old systems is required. ",
+    "'None' puts the filesystem directly on the device without a partition table, which some ",
+    "cameras, car stereos and embedded devices require."
+);
+const TOOLTIP_PART_NAME_INPUT: &str = concat!(
+    "Name stored in the GPT partition entry, shown by some operating systems and firmware. ",
+    "Leave empty to use the volume label. MBR partitions have no names."
+);
+const TOOLTIP_ALIGN_CHOICE: &str = concat!(
+    "Boundary created partitions start and end on. 1 MiB suits nearly all drives; flash with ",
+    "large erase blocks can benefit from more. 'Optimal I/O size' uses what the device reports."
+);
+const TOOLTIP_OFFSET_INPUT: &str = concat!(
+    "Start of the partition, e.g. 1M or 4096. Leave empty to start one alignment unit in. ",
+    "Some appliances expect a specific offset."
+);
+const TOOLTIP_BOOTABLE: &str =
+    "Mark the partition bootable (active on MBR, legacy BIOS bootable on GPT).";
+const TOOLTIP_HIDDEN: &str = "Mark the partition hidden so operating systems don't mount it.";
+const TOOLTIP_UEFI_BOOT: &str = concat!(
+    "Prepare bootable or firmware-update media: a FAT32 EFI System Partition on GPT, or an ",
+    "active FAT partition on MBR. Without a data partition the whole disk is formatted as ",
+    "vfat."
+);
+const TOOLTIP_DATA_PARTITION: &str = concat!(
+    "Add a data partition after a 550 MiB boot partition. The filesystem, label, partition ",
+    "type and flags above apply to the data partition."
+);
+const TOOLTIP_SD_SPEC: &str = concat!(
+    "Format an SD card the way the SD Association's SD Formatter does: an MBR partition and ",
+    "FAT16, FAT32 or exFAT chosen by the card's capacity, aligned to its erase blocks. Some ",
+    "cameras reject cards formatted any other way. The filesystem, allocation unit and ",
+    "partition settings are picked for you."
+);
+const TOOLTIP_ADVANCED_BTN: &str = concat!(
+    "Filesystem-specific settings such as the ext4 reserved blocks and journal, the FAT type ",
+    "and volume ID, or the btrfs checksum. The defaults suit most uses."
 );
 const TOOLTIP_START_BTN: &str = "Begin the formatting process with the selected options.";
+const TOOLTIP_RENAME_BTN: &str =
+    "Change the label of the selected filesystem to the volume label above, without formatting.";
+const TOOLTIP_CHECK_BTN: &str =
+    "Check the selected filesystem for errors without changing it. It is unmounted first.";
+const TOOLTIP_REPAIR_BTN: &str = concat!(
+    "Try to repair the selected filesystem. Worth trying before formatting a drive that ",
+    "stopped working. It is unmounted first."
+);
 const TOOLTIP_CANCEL_BTN: &str = "Cancel the ongoing formatting process.";
 const TOOLTIP_QUICK_FORMAT: &str =
     "Faster: skips data wipe and error scan. Uncheck for full format.";
 
 pub(crate) struct View {
     pub(crate) device_choice: Choice,
-    pub(crate) pt_choice: Choice,
     pub(crate) start_btn: Button,
+    pub(crate) rename_btn: Button,
+    pub(crate) check_btn: Button,
+    pub(crate) repair_btn: Button,
     pub(crate) cancel_btn: Button,
     pub(crate) progress: Progress,
     pub(crate) status: Frame,
@@ -75,18 +129,24 @@ impl View {
         tx: crossbeam_channel::Sender<Msg>,
         devices: Rc<RefCell<Vec<BlockDevice>>>,
     ) -> Self {
-        let mut win = Window::default().with_size(400, 500).with_label("diskfmt");
+        let mut win = Window::default().with_size(400, 700).with_label("diskfmt");
         win.set_xclass("diskfmt");
         win.set_icon(Some(PngImage::from_data(ICON).unwrap()));
         let mut col = Flex::default_fill().column();
         col.set_margins(10, 5, 10, 5);
         col.set_pad(5);
 
+        let mut row_dev = Flex::default().row();
         Frame::default().with_label("Target Device");
+        let mut show_all_chk = fltk::button::CheckButton::default().with_label("Show all drives");
+        show_all_chk.set_tooltip(TOOLTIP_SHOW_ALL);
+        row_dev.fixed(&show_all_chk, 120);
+        row_dev.end();
         let mut device_choice = Choice::default();
         device_choice.set_tooltip(TOOLTIP_DEVICE_CHOICE);
 
         Frame::default().with_label("Filesystem");
+        let mut row_fs = Flex::default().row();
         let mut fs_choice = Choice::default();
         fs_choice.set_tooltip(TOOLTIP_FS_CHOICE);
         let supported = detect_supported_fs();
@@ -96,7 +156,7 @@ impl View {
             fs_choice.deactivate();
         } else {
             for fs in &supported {
-                fs_choice.add_choice(fs);
+                fs_choice.add_choice(fs.name());
             }
             if let Some(def) = default_fs(&supported)
                 && let Some(idx) = supported.iter().position(|&x| x == def)
@@ -104,6 +164,14 @@ impl View {
                 fs_choice.set_value(idx as i32);
             }
         }
+        let mut advisor_btn = Button::default().with_label("Help me choose...");
+        advisor_btn.set_tooltip(TOOLTIP_ADVISOR_BTN);
+        if supported.is_empty() {
+            advisor_btn.deactivate();
+        }
+        row_fs.fixed(&advisor_btn, 130);
+        row_fs.set_pad(10);
+        row_fs.end();
 
         let mut size_label = Frame::default().with_label("Allocation Unit Size");
         let mut size_choice = Choice::default();
@@ -113,19 +181,82 @@ impl View {
         let mut label_input = Input::default();
         label_input.set_tooltip(TOOLTIP_LABEL_INPUT);
 
+        // Options that only apply when a whole disk is partitioned.
+        let mut disk_opts = Flex::default().column();
+        disk_opts.set_pad(5);
         Frame::default().with_label("Partition Table");
+        let mut row_pt = Flex::default().row();
         let mut pt_choice = Choice::default();
         pt_choice.set_tooltip(TOOLTIP_PT_CHOICE);
         pt_choice.add_choice("GPT (default)");
         pt_choice.add_choice("MBR (DOS)");
+        pt_choice.add_choice("None (superfloppy)");
         pt_choice.set_value(0);
+        let mut part_type_choice = Choice::default();
+        part_type_choice.set_tooltip(&partition_type_tooltip());
+        for t in PARTITION_TYPE_CHOICES {
+            part_type_choice.add_choice(t);
+        }
+        part_type_choice.set_value(0);
+        row_pt.set_pad(10);
+        row_pt.end();
+        let mut row_name = Flex::default().row();
+        let name_frame = Frame::default()
+            .with_label("Partition name")
+            .with_align(Align::Left | Align::Inside);
+        let mut part_name_input = Input::default();
+        part_name_input.set_tooltip(TOOLTIP_PART_NAME_INPUT);
+        row_name.fixed(&name_frame, 110);
+        row_name.end();
+        let mut row_align = Flex::default().row();
+        let align_frame = Frame::default()
+            .with_label("Alignment")
+            .with_align(Align::Left | Align::Inside);
+        let mut align_choice = Choice::default();
+        align_choice.set_tooltip(TOOLTIP_ALIGN_CHOICE);
+        for a in ALIGNMENT_CHOICES {
+            align_choice.add_choice(a);
+        }
+        align_choice.set_value(0);
+        let offset_frame = Frame::default()
+            .with_label("Offset")
+            .with_align(Align::Right | Align::Inside);
+        let mut offset_input = Input::default();
+        offset_input.set_tooltip(TOOLTIP_OFFSET_INPUT);
+        row_align.fixed(&align_frame, 110);
+        row_align.fixed(&offset_frame, 50);
+        row_align.end();
+        let row_flags = Flex::default().row();
+        let mut bootable_chk = fltk::button::CheckButton::default().with_label("Bootable");
+        bootable_chk.set_tooltip(TOOLTIP_BOOTABLE);
+        let mut hidden_chk = fltk::button::CheckButton::default().with_label("Hidden");
+        hidden_chk.set_tooltip(TOOLTIP_HIDDEN);
+        Frame::default();
+        row_flags.end();
+        let row_boot = Flex::default().row();
+        let mut uefi_chk = fltk::button::CheckButton::default().with_label("Bootable (UEFI)");
+        uefi_chk.set_tooltip(TOOLTIP_UEFI_BOOT);
+        let mut data_chk = fltk::button::CheckButton::default().with_label("Data partition");
+        data_chk.set_tooltip(TOOLTIP_DATA_PARTITION);
+        data_chk.deactivate();
+        row_boot.end();
+        let row_sd = Flex::default().row();
+        let mut sd_chk =
+            fltk::button::CheckButton::default().with_label("SD card (SD spec layout)");
+        sd_chk.set_tooltip(TOOLTIP_SD_SPEC);
+        row_sd.end();
+        disk_opts.end();
+        col.fixed(&disk_opts, 225);
 
         let mut row_quick = Flex::default().row();
         let mut quick_chk = fltk::button::CheckButton::default().with_label("Quick format");
         quick_chk.set_tooltip(TOOLTIP_QUICK_FORMAT);
         quick_chk.set_value(true);
         Frame::default();
+        let mut advanced_btn = Button::default().with_label("Advanced...");
+        advanced_btn.set_tooltip(TOOLTIP_ADVANCED_BTN);
         row_quick.fixed(&quick_chk, 80);
+        row_quick.fixed(&advanced_btn, 100);
         row_quick.end();
 
         let mut row_btn = Flex::default().row();
@@ -137,6 +268,16 @@ impl View {
         row_btn.set_pad(10);
         row_btn.end();
 
+        let mut row_tools = Flex::default().row();
+        let mut rename_btn = Button::default().with_label("Rename");
+        rename_btn.set_tooltip(TOOLTIP_RENAME_BTN);
+        let mut check_btn = Button::default().with_label("Check");
+        check_btn.set_tooltip(TOOLTIP_CHECK_BTN);
+        let mut repair_btn = Button::default().with_label("Repair");
+        repair_btn.set_tooltip(TOOLTIP_REPAIR_BTN);
+        row_tools.set_pad(10);
+        row_tools.end();
+
         let mut progress = Progress::default();
         progress.set_minimum(0.0);
         progress.set_maximum(100.0);
@@ -167,39 +308,199 @@ impl View {
             });
         }
 
-        let current_fs = fs_choice.choice();
-        size_label.set_label(size_label_text(current_fs.as_deref()));
-        fill_size_choices(&mut size_choice, current_fs.as_deref());
+        let current_fs = selected_fs(&supported, &fs_choice);
+        size_label.set_label(size_label_text(current_fs));
+        fill_size_choices(&mut size_choice, current_fs, None);
+        if !has_fields(current_fs) {
+            advanced_btn.deactivate();
+        }
+        let advanced: Rc<RefCell<AdvancedOptions>> = Rc::default();
 
         fs_choice.set_callback({
+            let supported = supported.clone();
             let mut size_choice = size_choice.clone();
             let mut size_label = size_label.clone();
+            let mut advanced_btn = advanced_btn.clone();
+            let advanced = advanced.clone();
+            let devices_ref = devices.clone();
+            let device_choice = device_choice.clone();
             move |c| {
-                let fs = c.choice().unwrap_or_else(|| "vfat".into());
-                size_label.set_label(size_label_text(Some(&fs)));
-                fill_size_choices(&mut size_choice, Some(&fs));
+                let fs = selected_fs(&supported, c);
+                size_label.set_label(size_label_text(fs));
+                let devs = devices_ref.borrow();
+                fill_size_choices(&mut size_choice, fs, selected_device(&devs, &device_choice));
+                // Advanced options are per filesystem.
+                *advanced.borrow_mut() = AdvancedOptions::default();
+                if has_fields(fs) {
+                    advanced_btn.activate();
+                } else {
+                    advanced_btn.deactivate();
+                }
             }
         });
 
-        device_choice.set_callback({
+        advisor_btn.set_callback({
+            let supported = supported.clone();
             let devices_ref = devices.clone();
+            let device_choice = device_choice.clone();
+            let mut fs_choice = fs_choice.clone();
             let mut pt_choice = pt_choice.clone();
-            move |c| {
-                let idx = c.value();
+            let targets: Rc<RefCell<Vec<Target>>> = Rc::default();
+            move |_| {
+                let Some(chosen) = targets_dialog(&targets.borrow()) else {
+                    return;
+                };
+                *targets.borrow_mut() = chosen;
                 let devs = devices_ref.borrow();
-                let is_partition = if idx >= 0 && (idx as usize) < devs.len() {
-                    devs[idx as usize].is_partition
-                } else {
-                    false
+                let device = selected_device(&devs, &device_choice);
+                let Some(advice) = advise(&targets.borrow(), &supported, device) else {
+                    return;
                 };
-                if is_partition {
+                if let Some(idx) = supported.iter().position(|&fs| fs == advice.fs) {
+                    fs_choice.set_value(idx as i32);
+                    fs_choice.do_callback();
+                }
+                if pt_choice.active_r() {
+                    pt_choice.set_value(match advice.table {
+                        PartitionTable::Dos => 1,
+                        _ => 0,
+                    });
+                    pt_choice.do_callback();
+                }
+                let mut msg = format!("Suggested: {} on {}", advice.fs, advice.table);
+                for w in &advice.warnings {
+                    msg.push_str(&format!("\n{w}"));
+                }
+                fltk::dialog::message_default(&msg);
+            }
+        });
+
+        advanced_btn.set_callback({
+            let supported = supported.clone();
+            let fs_choice = fs_choice.clone();
+            let advanced = advanced.clone();
+            move |_| {
+                let fs = selected_fs(&supported, &fs_choice);
+                let current = advanced.borrow().clone();
+                if let Some(adv) = advanced_dialog(fs, &current) {
+                    *advanced.borrow_mut() = adv;
+                }
+            }
+        });
+
+        pt_choice.set_callback({
+            let mut part_name_input = part_name_input.clone();
+            let mut part_type_choice = part_type_choice.clone();
+            let mut bootable_chk = bootable_chk.clone();
+            let mut hidden_chk = hidden_chk.clone();
+            move |c| {
+                let table = parse_partition_table_choice(c.choice().as_deref());
+                if table == Some(PartitionTable::Gpt) {
+                    part_name_input.activate();
+                } else {
+                    part_name_input.deactivate();
+                }
+                // A superfloppy has no partition to type or flag.
+                if table == Some(PartitionTable::None) {
+                    part_type_choice.deactivate();
+                    bootable_chk.deactivate();
+                    hidden_chk.deactivate();
+                } else {
+                    part_type_choice.activate();
+                    bootable_chk.activate();
+                    hidden_chk.activate();
+                }
+            }
+        });
+
+        uefi_chk.set_callback({
+            let mut data_chk = data_chk.clone();
+            move |c| {
+                if c.value() {
+                    data_chk.activate();
+                } else {
+                    data_chk.deactivate();
+                }
+            }
+        });
+
+        sd_chk.set_callback({
+            let supported = supported.clone();
+            let mut fs_choice = fs_choice.clone();
+            let mut advisor_btn = advisor_btn.clone();
+            let mut size_choice = size_choice.clone();
+            let mut pt_choice = pt_choice.clone();
+            let mut part_type_choice = part_type_choice.clone();
+            let mut part_name_input = part_name_input.clone();
+            let mut bootable_chk = bootable_chk.clone();
+            let mut hidden_chk = hidden_chk.clone();
+            let mut align_choice = align_choice.clone();
+            let mut offset_input = offset_input.clone();
+            let mut uefi_chk = uefi_chk.clone();
+            let mut data_chk = data_chk.clone();
+            let mut advanced_btn = advanced_btn.clone();
+            move |c| {
+                // The SD layout decides all of these. Unticking it leaves the
+                // partition widgets to the partition table choice.
+                if c.value() {
+                    fs_choice.deactivate();
+                    advisor_btn.deactivate();
+                    size_choice.deactivate();
                     pt_choice.deactivate();
+                    part_type_choice.deactivate();
+                    part_name_input.deactivate();
+                    bootable_chk.deactivate();
+                    hidden_chk.deactivate();
+                    align_choice.deactivate();
+                    offset_input.deactivate();
+                    uefi_chk.deactivate();
+                    data_chk.deactivate();
+                    advanced_btn.deactivate();
+                    return;
+                }
+                if !supported.is_empty() {
+                    fs_choice.activate();
+                    advisor_btn.activate();
+                }
+                size_choice.activate();
+                pt_choice.activate();
+                part_type_choice.activate();
+                align_choice.activate();
+                offset_input.activate();
+                uefi_chk.activate();
+                pt_choice.do_callback();
+                uefi_chk.do_callback();
+                if has_fields(selected_fs(&supported, &fs_choice)) {
+                    advanced_btn.activate();
+                }
+            }
+        });
+
+        device_choice.set_callback({
+            let devices_ref = devices.clone();
+            let mut disk_opts = disk_opts.clone();
+            let supported = supported.clone();
+            let fs_choice = fs_choice.clone();
+            let mut size_choice = size_choice.clone();
+            move |c| {
+                let devs = devices_ref.borrow();
+                let device = selected_device(&devs, c);
+                if device.is_some_and(|d| d.is_partition) {
+                    disk_opts.deactivate();
                 } else {
-                    pt_choice.activate();
+                    disk_opts.activate();
                 }
+                // Recommendations depend on the device's capacity.
+                let fs = selected_fs(&supported, &fs_choice);
+                fill_size_choices(&mut size_choice, fs, device);
             }
         });
 
+        show_all_chk.set_callback({
+            let tx = tx.clone();
+            move |c| tx.emit(Msg::ShowAll(c.value()))
+        });
+
         cancel_btn.set_callback({
             let tx = tx.clone();
             move |_| {
@@ -207,6 +508,77 @@ impl View {
             }
         });
 
+        rename_btn.set_callback({
+            let tx = tx.clone();
+            let devices_ref = devices.clone();
+            let device_choice = device_choice.clone();
+            let label_input = label_input.clone();
+            move |_| {
+                let idx = device_choice.value();
+                let devs = devices_ref.borrow();
+                if idx < 0 || (idx as usize) >= devs.len() {
+                    return;
+                }
+                let device = &devs[idx as usize];
+                let label = label_input.value();
+                if let Err(err) = crate::utils::validate_relabel(device, &label) {
+                    fltk::dialog::message_default(&format!("Invalid label: {}", err));
+                    return;
+                }
+                tx.emit(Msg::Rename {
+                    obj_path: device.object_path.clone(),
+                    label,
+                });
+            }
+        });
+
+        check_btn.set_callback({
+            let tx = tx.clone();
+            let devices_ref = devices.clone();
+            let device_choice = device_choice.clone();
+            move |_| {
+                let idx = device_choice.value();
+                let devs = devices_ref.borrow();
+                if idx < 0 || (idx as usize) >= devs.len() {
+                    return;
+                }
+                tx.emit(Msg::Fsck {
+                    obj_path: devs[idx as usize].object_path.clone(),
+                    repair: false,
+                });
+            }
+        });
+
+        repair_btn.set_callback({
+            let tx = tx.clone();
+            let devices_ref = devices.clone();
+            let device_choice = device_choice.clone();
+            move |_| {
+                let idx = device_choice.value();
+                let devs = devices_ref.borrow();
+                if idx < 0 || (idx as usize) >= devs.len() {
+                    return;
+                }
+                let device = &devs[idx as usize];
+                let ans = fltk::dialog::choice2_default(
+                    &format!(
+                        "Repairing modifies the filesystem on {}. Back up what you can first. Continue?",
+                        device.dev_path
+                    ),
+                    "No",
+                    "Yes",
+                    "Cancel",
+                );
+                if ans != Some(1) {
+                    return;
+                }
+                tx.emit(Msg::Fsck {
+                    obj_path: device.object_path.clone(),
+                    repair: true,
+                });
+            }
+        });
+
         start_btn.set_callback({
             let tx = tx.clone();
             let supported_fs = supported.clone();
@@ -217,6 +589,16 @@ impl View {
             let size_choice = size_choice.clone();
             let quick_chk = quick_chk.clone();
             let pt_choice = pt_choice.clone();
+            let part_name_input = part_name_input.clone();
+            let part_type_choice = part_type_choice.clone();
+            let align_choice = align_choice.clone();
+            let offset_input = offset_input.clone();
+            let bootable_chk = bootable_chk.clone();
+            let hidden_chk = hidden_chk.clone();
+            let uefi_chk = uefi_chk.clone();
+            let data_chk = data_chk.clone();
+            let sd_chk = sd_chk.clone();
+            let advanced = advanced.clone();
             move |_| {
                 if supported_fs.is_empty() {
                     fltk::dialog::message_default(
@@ -230,27 +612,48 @@ impl View {
                     return;
                 }
                 let device = &devs[idx as usize];
-                let obj_path = device.object_path.clone();
-                let ans = fltk::dialog::choice2_default(
-                    &format!(
-                        "WARNING: Formatting will erase all data on {}. Continue?",
-                        obj_path
-                    ),
-                    "No",
-                    "Yes",
-                    "Cancel",
-                );
-                if ans != Some(1) {
+                if let Some(why) = &device.system_use {
+                    fltk::dialog::message_default(&format!(
+                        "{} can't be formatted: its disk {why}, and the running system depends on it.",
+                        device.dev_path
+                    ));
                     return;
                 }
-                let fs = fs_choice.choice().unwrap_or_else(|| "vfat".into());
+                let obj_path = device.object_path.clone();
+                let sd_layout = if sd_chk.active_r() && sd_chk.value() {
+                    match crate::utils::sd_layout(device) {
+                        Ok(l) if supported_fs.contains(&l.fs()) => Some(l),
+                        Ok(l) => {
+                            fltk::dialog::message_default(&format!(
+                                "The SD specification puts {} on this card, which isn't installed.",
+                                l.fs()
+                            ));
+                            return;
+                        }
+                        Err(err) => {
+                            fltk::dialog::message_default(&format!("Invalid target: {}", err));
+                            return;
+                        }
+                    }
+                } else {
+                    None
+                };
+                let fs = match &sd_layout {
+                    Some(l) => l.fs(),
+                    None => selected_fs(&supported_fs, &fs_choice),
+                };
                 let label = {
                     let s = label_input.value();
                     if s.is_empty() { None } else { Some(s) }
                 };
-                let size = crate::utils::parse_size_choice_label(size_choice.choice().as_deref());
-                let partition_table = parse_partition_table_choice(pt_choice.choice().as_deref());
-                let opts = match crate::utils::build_format_options(
+                let (size, partition_table) = match &sd_layout {
+                    Some(l) => (Some(l.alloc_unit()), Some(PartitionTable::Dos)),
+                    None => (
+                        crate::utils::parse_size_choice_label(size_choice.choice().as_deref()),
+                        parse_partition_table_choice(pt_choice.choice().as_deref()),
+                    ),
+                };
+                let mut opts = match crate::utils::build_format_options(
                     fs,
                     label,
                     quick_chk.value(),
@@ -259,18 +662,123 @@ impl View {
                 ) {
                     Ok(o) => o,
                     Err(err) => {
-                        fltk::dialog::message_default(&format!("Invalid label: {}", err));
+                        fltk::dialog::message_default(&format!("Invalid options: {}", err));
                         return;
                     }
                 };
-                tx.emit(Msg::Start { obj_path, opts });
+                if let Some(err) = device.size_bytes.and_then(|s| opts.fs.validate_volume_size(s)) {
+                    fltk::dialog::message_default(&format!("Invalid target: {}", err));
+                    return;
+                }
+                if sd_layout.is_none() && part_type_choice.active_r() {
+                    opts.partition_type =
+                        parse_partition_type_choice(part_type_choice.choice().as_deref());
+                }
+                let part_name = part_name_input.value();
+                if part_name_input.active_r() && !part_name.is_empty() {
+                    if let Some(err) =
+                        crate::utils::validate_partition_name(&part_name, opts.partition_table)
+                    {
+                        fltk::dialog::message_default(&format!("Invalid partition name: {}", err));
+                        return;
+                    }
+                    opts.partition_name = Some(part_name);
+                }
+                if align_choice.active_r() {
+                    opts.alignment = parse_alignment_choice(align_choice.choice().as_deref());
+                    let offset = offset_input.value();
+                    if !offset.trim().is_empty() {
+                        opts.partition_offset = match crate::utils::parse_byte_size(&offset) {
+                            Ok(o) => Some(o),
+                            Err(err) => {
+                                fltk::dialog::message_default(&format!("Invalid offset: {}", err));
+                                return;
+                            }
+                        };
+                    }
+                    if let Some(err) = crate::utils::validate_partition_layout(&opts) {
+                        fltk::dialog::message_default(&format!("Invalid partition layout: {}", err));
+                        return;
+                    }
+                }
+                opts.expected = Some(device.fingerprint());
+                opts.partition_flags.bootable = bootable_chk.active_r() && bootable_chk.value();
+                opts.partition_flags.hidden = hidden_chk.active_r() && hidden_chk.value();
+                if sd_layout.is_some() {
+                    opts.sd_spec = true;
+                } else {
+                    opts.advanced = advanced.borrow().clone();
+                }
+                if uefi_chk.active_r() && uefi_chk.value() {
+                    let preset = BootPreset {
+                        esp_size: DEFAULT_ESP_SIZE,
+                        data_partition: data_chk.value(),
+                    };
+                    if let Some(err) = crate::utils::validate_boot_preset(&opts, &preset) {
+                        fltk::dialog::message_default(&format!("Invalid preset: {}", err));
+                        return;
+                    }
+                    opts.boot_preset = Some(preset);
+                }
+                let mut warnings = crate::utils::member_warnings(device, &devs);
+                warnings.extend(crate::utils::preflight_warnings(&opts, device));
+                if !warnings.is_empty() {
+                    let ans = fltk::dialog::choice2_default(
+                        &format!(
+                            "Check these before formatting:\n\n{}\n\nFormat anyway?",
+                            warnings.join("\n")
+                        ),
+                        "No",
+                        "Yes",
+                        "Cancel",
+                    );
+                    if ans != Some(1) {
+                        return;
+                    }
+                }
+                // Asked once, after everything above had its say.
+                let mut details = Vec::new();
+                if let Some(l) = sd_layout {
+                    details.push(format!("SD spec layout: {l}"));
+                }
+                if !device.removable {
+                    details.push(format!(
+                        "{} is a {}, not removable media.",
+                        device.dev_path,
+                        crate::utils::fixed_drive(device)
+                    ));
+                }
+                details.extend(
+                    crate::utils::preflight_notes(&opts, device)
+                        .into_iter()
+                        .map(|n| format!("Note: {n}")),
+                );
+                let details: String = details.iter().map(|d| format!("\n\n{d}")).collect();
+                let ans = fltk::dialog::choice2_default(
+                    &format!(
+                        "WARNING: Formatting will erase all data on {}. Continue?{details}",
+                        device.dev_path
+                    ),
+                    "No",
+                    "Yes",
+                    "Cancel",
+                );
+                if ans != Some(1) {
+                    return;
+                }
+                tx.emit(Msg::Start {
+                    obj_path,
+                    opts: Box::new(opts),
+                });
             }
         });
 
         Self {
             device_choice,
-            pt_choice,
             start_btn,
+            rename_btn,
+            check_btn,
+            repair_btn,
             cancel_btn,
             progress,
             status,
@@ -1,6 +1,7 @@
 mod backends;
 pub mod cli;
 mod common;
+mod compat;
 pub mod config;
 pub mod style;
 #[cfg(all(feature = "a11y", not(feature = "gui")))]
@@ -1,33 +1,15 @@
-use crate::backends::{BlockDevice, human_size};
-use crate::backends::{FormatOptions, PartitionTable};
+use crate::backends::filesystem::{AllocProfile, AllocUnit, GIB, TIB};
+use crate::backends::sd::SdLayout;
+use crate::backends::{
+    AdvancedOptions, Alignment, BootPreset, Filesystem, FormatOptions, PartitionFlags,
+    PartitionTable, PartitionType,
+};
+use crate::backends::{BlockDevice, GPT_NAME_MAX, MIN_ESP_SIZE, human_size};
+use crate::backends::{is_guid, parse_mbr_type};
 use std::process::{Command, Stdio};
 
-const FAT_INVALID_CHARS: [char; 10] = ['"', '*', '/', ':', '<', '>', '?', '\\', '|', '\0'];
-
-fn has_fat_invalid_chars(label: &str) -> bool {
-    label
-        .chars()
-        .any(|c| FAT_INVALID_CHARS.contains(&c) || c.is_control())
-}
-
-fn mkfs_present(fs: &str) -> bool {
-    let candidates: &[&[&str]] = match fs {
-        "vfat" => &[&["mkfs.vfat"]],
-        "exfat" => &[&["mkfs.exfat"]],
-        "ntfs" => &[&["mkfs.ntfs"], &["mkntfs"]],
-        "ext4" => &[&["mkfs.ext4"], &["mke2fs"]],
-        "xfs" => &[&["mkfs.xfs"]],
-        "btrfs" => &[&["mkfs.btrfs"]],
-        _ => &[],
-    };
-    for group in candidates {
-        for bin in *group {
-            if which(bin) {
-                return true;
-            }
-        }
-    }
-    false
+fn mkfs_present(fs: Filesystem) -> bool {
+    fs.info().mkfs_tools.iter().any(|bin| which(bin))
 }
 
 fn which(bin: &str) -> bool {
@@ -40,9 +22,11 @@ fn which(bin: &str) -> bool {
         .unwrap_or(false)
 }
 
-pub(crate) fn detect_supported_fs() -> Vec<&'static str> {
-    let all = ["exfat", "vfat", "ntfs", "ext4", "xfs", "btrfs"];
-    all.into_iter().filter(|fs| mkfs_present(fs)).collect()
+pub(crate) fn detect_supported_fs() -> Vec<Filesystem> {
+    Filesystem::ALL
+        .into_iter()
+        .filter(|&fs| mkfs_present(fs))
+        .collect()
 }
 
 pub(crate) fn device_display(dev: &BlockDevice) -> String {
@@ -61,6 +45,9 @@ pub(crate) fn device_display(dev: &BlockDevice) -> String {
     if let Some(vm) = &dev.vendor_model {
         extras.push(vm.clone());
     }
+    if !dev.removable {
+        extras.push(fixed_drive(dev));
+    }
     if let Some(fs) = &dev.fs_type {
         if !fs.is_empty() {
             extras.push(fs.clone());
@@ -71,6 +58,20 @@ pub(crate) fn device_display(dev: &BlockDevice) -> String {
             extras.push(format!("\"{}\"", lbl));
         }
     }
+    if let Some(name) = &dev.partition_name {
+        extras.push(format!("partition name \"{}\"", name));
+    }
+    if dev.is_misaligned()
+        && let Some(offset) = dev.partition_offset
+    {
+        extras.push(format!("misaligned: starts at {offset} bytes"));
+    }
+    if let Some(of) = membership(dev) {
+        extras.push(format!("part of {of}"));
+    }
+    if let Some(why) = &dev.system_use {
+        extras.push(format!("system disk: {why}"));
+    }
     let base = if !dev.dev_path.is_empty() {
         &dev.dev_path
     } else {
@@ -83,71 +84,248 @@ pub(crate) fn device_display(dev: &BlockDevice) -> String {
     }
 }
 
-pub(crate) fn default_fs(supported: &[&str]) -> Option<&'static str> {
-    ["exfat", "vfat", "ext4", "ntfs", "xfs", "btrfs"]
+pub(crate) fn default_fs(supported: &[Filesystem]) -> Option<Filesystem> {
+    Filesystem::PREFERENCE
         .into_iter()
-        .find(|&pref| supported.contains(&pref))
-        .map(|v| v as _)
+        .find(|pref| supported.contains(pref))
 }
 
-fn validate_label(label: &str, fs: &str) -> Option<String> {
-    if label.is_empty() {
-        return None;
+/// Checks `label` against the filesystem already on `dev`, for renaming it in
+/// place. An empty label clears it.
+pub(crate) fn validate_relabel(dev: &BlockDevice, label: &str) -> Result<(), String> {
+    let Some(fs) = dev.fs_type.as_deref().filter(|fs| !fs.is_empty()) else {
+        return Err(format!("{} has no filesystem to rename", dev.dev_path));
+    };
+    // Filesystems diskfmt can't create (iso9660, ...) are left to UDisks.
+    let Ok(fs) = fs.parse::<Filesystem>() else {
+        return Ok(());
+    };
+    match fs.validate_label(label) {
+        Some(err) => Err(err),
+        None => Ok(()),
     }
+}
 
-    match fs {
-        "vfat" => {
-            if label.len() > 11 {
-                return Some("vfat: max 11 bytes".to_string());
-            }
-            if has_fat_invalid_chars(label) {
-                return Some("vfat: invalid characters".to_string());
-            }
+/// Finds a listed device from a target given as a device node
+/// (`/dev/sdb1`), a `/dev/disk/by-*` link, `LABEL=`, `UUID=`, `serial:` or a
+/// UDisks object path. A serial names a whole drive, so partitions are only
+/// considered when no disk matches.
+pub(crate) fn resolve_device<'a>(
+    devs: &'a [BlockDevice],
+    target: &str,
+) -> Result<&'a BlockDevice, String> {
+    let matches: Vec<&BlockDevice> = if let Some(label) = target.strip_prefix("LABEL=") {
+        devs.iter()
+            .filter(|d| d.label.as_deref() == Some(label))
+            .collect()
+    } else if let Some(uuid) = target.strip_prefix("UUID=") {
+        devs.iter()
+            .filter(|d| {
+                d.uuid
+                    .as_deref()
+                    .is_some_and(|u| u.eq_ignore_ascii_case(uuid))
+            })
+            .collect()
+    } else if let Some(serial) = target.strip_prefix("serial:") {
+        let on_drive: Vec<&BlockDevice> = devs
+            .iter()
+            .filter(|d| d.serial.as_deref() == Some(serial))
+            .collect();
+        let disks: Vec<&BlockDevice> = on_drive
+            .iter()
+            .copied()
+            .filter(|d| !d.is_partition)
+            .collect();
+        if disks.is_empty() { on_drive } else { disks }
+    } else {
+        // Links other than the ones UDisks knows about still lead to a node.
+        let node = std::fs::canonicalize(target)
+            .ok()
+            .map(|p| p.to_string_lossy().into_owned());
+        devs.iter()
+            .filter(|d| {
+                d.object_path == target
+                    || d.dev_path == target
+                    || d.links.iter().any(|l| l == target)
+                    || node.as_deref() == Some(d.dev_path.as_str())
+            })
+            .collect()
+    };
+    match matches.as_slice() {
+        [] => Err(format!("no listed device matches {target}")),
+        [dev] => Ok(dev),
+        many => {
+            let paths: Vec<&str> = many.iter().map(|d| d.dev_path.as_str()).collect();
+            Err(format!(
+                "{target} is ambiguous, it matches {}",
+                paths.join(", ")
+            ))
         }
-        "exfat" => {
-            if label.chars().count() > 15 {
-                return Some("exfat: max 15 characters".to_string());
-            }
-            if has_fat_invalid_chars(label) {
-                return Some("exfat: invalid characters".to_string());
-            }
+    }
+}
+
+/// The ways `resolve_device` accepts to name `dev`.
+pub(crate) fn device_identifiers(dev: &BlockDevice) -> Vec<String> {
+    let mut ids = vec![dev.dev_path.clone()];
+    if let Some(lbl) = dev.label.as_deref().filter(|l| !l.is_empty()) {
+        ids.push(format!("LABEL={lbl}"));
+    }
+    if let Some(uuid) = &dev.uuid {
+        ids.push(format!("UUID={uuid}"));
+    }
+    if let Some(serial) = dev.serial.as_deref().filter(|_| !dev.is_partition) {
+        ids.push(format!("serial:{serial}"));
+    }
+    ids.extend(dev.links.iter().cloned());
+    ids
+}
+
+/// The partitions of `disk` among `devs`, by the partition table they are in.
+pub(crate) fn partitions_of<'a>(
+    devs: &'a [BlockDevice],
+    disk: &BlockDevice,
+) -> Vec<&'a BlockDevice> {
+    if disk.is_partition {
+        return Vec::new();
+    }
+    devs.iter()
+        .filter(|d| d.table_object.as_deref() == Some(disk.object_path.as_str()))
+        .collect()
+}
+
+/// What a member signature makes a device part of, with its article.
+fn member_kind(fs_type: &str) -> Option<(&'static str, &'static str)> {
+    match fs_type {
+        "linux_raid_member" => Some(("a", "RAID array")),
+        "LVM2_member" => Some(("an", "LVM volume group")),
+        "zfs_member" => Some(("a", "ZFS pool")),
+        t if t.ends_with("_raid_member") => Some(("a", "firmware RAID array")),
+        t if t.ends_with("_member") => Some(("a", "multi-device volume")),
+        _ => None,
+    }
+}
+
+/// "LVM volume group backup", or "an LVM volume group" when the name isn't
+/// known, for a device with a member signature.
+fn membership(dev: &BlockDevice) -> Option<String> {
+    let (article, kind) = member_kind(dev.fs_type.as_deref()?)?;
+    Some(match &dev.member_of {
+        Some(name) => format!("{kind} {name}"),
+        None => format!("{article} {kind}"),
+    })
+}
+
+/// Warnings for `dev`, or for a disk its partitions, carrying RAID, LVM or
+/// ZFS member signatures.
+pub(crate) fn member_warnings(dev: &BlockDevice, devs: &[BlockDevice]) -> Vec<String> {
+    std::iter::once(dev)
+        .chain(partitions_of(devs, dev))
+        .filter_map(|d| {
+            let of = membership(d)?;
+            Some(format!(
+                "{} is part of {of}; formatting it destroys that member's data",
+                d.dev_path
+            ))
+        })
+        .collect()
+}
+
+/// Several lines describing `dev` and what's on it, for confirming a format.
+pub(crate) fn device_details(dev: &BlockDevice, devs: &[BlockDevice]) -> String {
+    let mut lines = vec![dev.dev_path.clone()];
+    if let Some(vm) = &dev.vendor_model {
+        lines.push(format!("  Model: {vm}"));
+    }
+    if let Some(size) = dev.size_bytes {
+        lines.push(format!("  Size: {}", human_size(size)));
+    }
+    if !dev.removable {
+        lines.push(format!("  Type: {}", fixed_drive(dev)));
+    }
+    let volumes: Vec<&BlockDevice> = std::iter::once(dev)
+        .filter(|d| d.fs_type.as_deref().is_some_and(|fs| !fs.is_empty()))
+        .chain(partitions_of(devs, dev))
+        .collect();
+    if !dev.is_partition {
+        lines.push(format!("  Partitions: {}", partitions_of(devs, dev).len()));
+    }
+    for v in volumes {
+        let mut line = format!("  {}:", v.dev_path);
+        match v.fs_type.as_deref() {
+            Some(fs) if !fs.is_empty() => line.push_str(&format!(" {fs}")),
+            _ => line.push_str(" no filesystem"),
         }
-        "ntfs" => {
-            if label.chars().count() > 32 {
-                return Some("ntfs: max 32 characters".to_string());
-            }
-            if label.contains('\0') {
-                return Some("ntfs: invalid characters".to_string());
-            }
+        if let Some(lbl) = v.label.as_deref().filter(|l| !l.is_empty()) {
+            line.push_str(&format!(" \"{lbl}\""));
         }
-        "ext4" => {
-            if label.len() > 16 {
-                return Some("ext4: max 16 bytes".to_string());
-            }
-            if label.contains('\0') || label.contains('/') {
-                return Some("ext4: invalid characters".to_string());
-            }
+        if let Some(size) = v.size_bytes {
+            line.push_str(&format!(", {}", human_size(size)));
         }
-        "xfs" => {
-            if label.len() > 12 {
-                return Some("xfs: max 12 bytes".to_string());
-            }
-            if label.contains('\0') {
-                return Some("xfs: invalid characters".to_string());
-            }
+        if !v.mount_points.is_empty() {
+            line.push_str(&format!(", mounted at {}", v.mount_points.join(", ")));
         }
-        "btrfs" => {
-            if label.len() > 255 {
-                return Some("btrfs: max 255 bytes".to_string());
-            }
-            if label.contains('\0') {
-                return Some("btrfs: invalid characters".to_string());
-            }
+        if let Some(of) = membership(v) {
+            line.push_str(&format!(", part of {of}"));
         }
-        _ => {}
+        lines.push(line);
     }
+    lines.join("\n")
+}
 
-    None
+/// "fixed USB drive" and the like, for devices that aren't removable media.
+pub(crate) fn fixed_drive(dev: &BlockDevice) -> String {
+    match dev.bus.as_deref() {
+        Some("usb") => "fixed USB drive".to_string(),
+        Some("ieee1394") => "fixed FireWire drive".to_string(),
+        Some(bus) => format!("fixed {bus} drive"),
+        None => "fixed drive".to_string(),
+    }
+}
+
+/// Whether `answer` names `dev`: its device name ("sdb" or "/dev/sdb") or
+/// its label.
+pub(crate) fn confirms_device(answer: &str, dev: &BlockDevice) -> bool {
+    let answer = answer.trim();
+    if answer.is_empty() {
+        return false;
+    }
+    let name = dev.dev_path.rsplit('/').next().unwrap_or_default();
+    answer == dev.dev_path || answer == name || dev.label.as_deref() == Some(answer)
+}
+
+/// SD cards, in a slot or a USB card reader, get the SD Association's
+/// allocation units, everything else Windows' defaults.
+pub(crate) fn alloc_profile(dev: &BlockDevice) -> AllocProfile {
+    if dev.sd_card {
+        AllocProfile::SdCard
+    } else {
+        AllocProfile::Windows
+    }
+}
+
+pub(crate) fn recommended_alloc_unit(fs: Filesystem, dev: &BlockDevice) -> Option<u64> {
+    fs.recommended_alloc_unit(dev.size_bytes?, alloc_profile(dev))
+}
+
+/// The SD specification layout for `dev`, which must be a whole card.
+pub(crate) fn sd_layout(dev: &BlockDevice) -> Result<SdLayout, String> {
+    if dev.is_partition {
+        return Err("SD spec formatting needs the whole card, not a partition".to_string());
+    }
+    let Some(size) = dev.size_bytes else {
+        return Err(format!("the size of {} is unknown", dev.dev_path));
+    };
+    SdLayout::for_capacity(size)
+        .ok_or_else(|| format!("{} is larger than an SDXC card", human_size(size)))
+}
+
+/// How `--size` and the GUI list spell an allocation unit.
+pub(crate) fn size_choice_label(fs: Filesystem, unit: u64) -> String {
+    match fs.info().alloc_unit {
+        AllocUnit::Sectors(_) if unit == 1 => "1 sector".to_string(),
+        AllocUnit::Sectors(_) => format!("{unit} sectors"),
+        _ => format!("{unit} bytes"),
+    }
 }
 
 pub(crate) fn parse_size_choice_label(label: Option<&str>) -> Option<u64> {
@@ -162,22 +340,242 @@ pub(crate) fn parse_size_choice_label(label: Option<&str>) -> Option<u64> {
 }
 
 pub(crate) fn build_format_options(
-    fs: String,
+    fs: Filesystem,
     label: Option<String>,
     quick: bool,
     cluster_or_block_size: Option<u64>,
     partition_table: Option<PartitionTable>,
 ) -> Result<FormatOptions, String> {
     if let Some(ref lbl) = label {
-        if let Some(err) = validate_label(lbl, &fs) {
+        if let Some(err) = fs.validate_label(lbl) {
             return Err(err);
         }
     }
+    if let Some(size) = cluster_or_block_size
+        && let Some(err) = fs.validate_alloc_unit(size)
+    {
+        return Err(err);
+    }
     Ok(FormatOptions {
         fs,
         label,
         quick,
         cluster_or_block_size,
         partition_table,
+        partition_type: None,
+        partition_flags: PartitionFlags::default(),
+        partition_name: None,
+        boot_preset: None,
+        alignment: Alignment::default(),
+        partition_offset: None,
+        sd_spec: false,
+        expected: None,
+        advanced: AdvancedOptions::default(),
     })
 }
+
+/// Combinations that mkfs accepts (or would fail on late) but that are
+/// unlikely to be what the user wants, for formatting `opts` onto `dev`.
+pub(crate) fn preflight_warnings(opts: &FormatOptions, dev: &BlockDevice) -> Vec<String> {
+    // Most clusters a FAT can address.
+    const FAT12_MAX_CLUSTERS: u64 = 4_084;
+    const FAT16_MAX_CLUSTERS: u64 = 65_524;
+    const FAT32_MAX_CLUSTERS: u64 = 0x0FFF_FFF5;
+
+    let mut warnings = Vec::new();
+    // The SD specification settles the rest itself.
+    if opts.sd_spec {
+        if !dev.sd_card {
+            warnings.push(format!(
+                "{} doesn't look like an SD card; the SD spec layout is meant for cards in an SD slot or reader",
+                dev.dev_path
+            ));
+        }
+        return warnings;
+    }
+    let Some(size) = dev.size_bytes else {
+        return warnings;
+    };
+    let whole_disk = !dev.is_partition && opts.partition_table != Some(PartitionTable::None);
+    if whole_disk && opts.partition_table == Some(PartitionTable::Dos) && size > 2 * TIB {
+        warnings.push(format!(
+            "MBR can only use the first 2 TiB of this {} disk; use GPT",
+            human_size(size)
+        ));
+    }
+    match opts.fs {
+        Filesystem::Vfat => {
+            if size > 2 * TIB {
+                warnings.push(format!(
+                    "{} volumes over 2 TB ({}) aren't readable by Windows and most devices",
+                    fat_name(opts),
+                    human_size(size)
+                ));
+            }
+            if let Some(spc) = opts.cluster_or_block_size {
+                let max = match opts.advanced.fat_size {
+                    Some(12) => FAT12_MAX_CLUSTERS,
+                    Some(16) => FAT16_MAX_CLUSTERS,
+                    _ => FAT32_MAX_CLUSTERS,
+                };
+                let needed = size.div_ceil(max * 512).next_power_of_two();
+                if spc < needed {
+                    warnings.push(format!(
+                        "{} per cluster is too small for {}; use at least {}",
+                        size_choice_label(opts.fs, spc),
+                        human_size(size),
+                        size_choice_label(opts.fs, needed)
+                    ));
+                }
+            }
+        }
+        Filesystem::Btrfs if size < GIB => {
+            warnings.push(format!(
+                "btrfs spends much of a {} volume on metadata; ext4 or vfat suit small sticks better",
+                human_size(size)
+            ));
+        }
+        _ => {}
+    }
+    warnings
+}
+
+/// Limits of a sound choice worth knowing before formatting `opts` onto
+/// `dev`; unlike `preflight_warnings` they don't stop a format.
+pub(crate) fn preflight_notes(opts: &FormatOptions, dev: &BlockDevice) -> Vec<String> {
+    let mut notes = Vec::new();
+    if opts.fs == Filesystem::Vfat && dev.size_bytes.is_some_and(|s| s > 4 * GIB) {
+        notes.push(format!(
+            "{} can't store files of 4 GB or more",
+            fat_name(opts)
+        ));
+    }
+    notes
+}
+
+/// The FAT type a vfat format makes: the chosen one, or FAT32, which
+/// mkfs.fat picks for any volume big enough to matter.
+fn fat_name(opts: &FormatOptions) -> &'static str {
+    match opts.advanced.fat_size {
+        Some(12) => "FAT12",
+        Some(16) => "FAT16",
+        _ => "FAT32",
+    }
+}
+
+/// Partition names only exist on GPT and hold 36 UTF-16 code units.
+pub(crate) fn validate_partition_name(name: &str, table: Option<PartitionTable>) -> Option<String> {
+    if matches!(table, Some(PartitionTable::Dos | PartitionTable::None)) {
+        return Some("only GPT partitions have names".to_string());
+    }
+    if name.encode_utf16().count() > GPT_NAME_MAX {
+        return Some(format!("max {GPT_NAME_MAX} characters"));
+    }
+    if name.contains('\0') {
+        return Some("invalid characters".to_string());
+    }
+    None
+}
+
+/// Parses a byte count with an optional K, M or G suffix (powers of 1024,
+/// "KiB" style spellings too): "4096", "64K", "1MiB".
+pub(crate) fn parse_byte_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(digits);
+    let num: u64 = num.parse().map_err(|_| format!("invalid size '{s}'"))?;
+    let mult = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1024,
+        "m" | "mib" => 1024 * 1024,
+        "g" | "gib" => 1024 * 1024 * 1024,
+        _ => {
+            return Err(format!(
+                "invalid size '{s}' (expected bytes or a K/M/G suffix)"
+            ));
+        }
+    };
+    num.checked_mul(mult)
+        .ok_or_else(|| format!("size '{s}' is too large"))
+}
+
+/// Parses `--align`: "optimal" or a size.
+pub(crate) fn parse_alignment(s: &str) -> Result<Alignment, String> {
+    if s.eq_ignore_ascii_case("optimal") {
+        return Ok(Alignment::OptimalIo);
+    }
+    parse_byte_size(s).map(Alignment::Bytes)
+}
+
+/// Checks the alignment, first partition offset and partition options of a
+/// whole-disk format.
+pub(crate) fn validate_partition_layout(opts: &FormatOptions) -> Option<String> {
+    if opts.partition_table == Some(PartitionTable::None) {
+        if opts.partition_type.is_some() {
+            return Some("a device without a partition table has no partition type".to_string());
+        }
+        if opts.partition_flags.bootable || opts.partition_flags.hidden {
+            return Some("a device without a partition table has no partition flags".to_string());
+        }
+    }
+    if let Alignment::Bytes(bytes) = opts.alignment
+        && (bytes == 0 || bytes % 512 != 0)
+    {
+        return Some("the alignment must be a non-zero multiple of 512 bytes".to_string());
+    }
+    let offset = opts.partition_offset?;
+    if offset % 512 != 0 {
+        return Some("the partition offset must be a multiple of 512 bytes".to_string());
+    }
+    let table = opts.partition_table.unwrap_or(PartitionTable::Gpt);
+    if table == PartitionTable::None {
+        return Some("a device without a partition table has no partition offset".to_string());
+    }
+    let min = table.first_usable_byte();
+    if offset < min {
+        return Some(format!("the partition offset must be at least {min} bytes"));
+    }
+    None
+}
+
+/// Without a data partition the boot partition is the only filesystem, and it
+/// has to be FAT.
+pub(crate) fn validate_boot_preset(opts: &FormatOptions, preset: &BootPreset) -> Option<String> {
+    if !preset.data_partition && opts.fs != Filesystem::Vfat {
+        return Some(format!(
+            "the bootable (UEFI) preset formats the boot partition as vfat; \
+             add a data partition to use {}",
+            opts.fs
+        ));
+    }
+    if !preset.data_partition && opts.advanced.fat_size.is_some_and(|bits| bits != 32) {
+        return Some("the bootable (UEFI) preset formats the boot partition as FAT32".to_string());
+    }
+    if preset.esp_size < MIN_ESP_SIZE {
+        return Some(format!(
+            "the boot partition must be at least {} MiB to hold FAT32",
+            MIN_ESP_SIZE / 1024 / 1024
+        ));
+    }
+    None
+}
+
+/// Parses a partition type name ("auto", "esp", "basic-data", "linux", ...), a GPT
+/// type GUID or an MBR type byte. "auto" yields `None`.
+pub(crate) fn parse_partition_type(s: &str) -> Result<Option<PartitionType>, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "auto" => Ok(None),
+        "esp" | "efi" => Ok(Some(PartitionType::EfiSystem)),
+        "basic-data" | "msdata" => Ok(Some(PartitionType::MicrosoftBasicData)),
+        "linux" => Ok(Some(PartitionType::LinuxFilesystem)),
+        "swap" => Ok(Some(PartitionType::LinuxSwap)),
+        "hfsplus" => Ok(Some(PartitionType::AppleHfsPlus)),
+        other if is_guid(other) || parse_mbr_type(other).is_some() => {
+            Ok(Some(PartitionType::Custom(other.to_string())))
+        }
+        _ => Err(format!(
+            "unknown partition type '{s}' (expected auto, esp, basic-data, linux, swap, hfsplus, \
+             a GUID or 0xNN)"
+        )),
+    }
+}